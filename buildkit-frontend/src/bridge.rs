@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use buildkit_llb::prelude::*;
+use buildkit_proto::{
+    fsutil::types::Stat,
+    moby::buildkit::v1::frontend::{
+        self, llb_bridge_client::LlbBridgeClient, result::Result as RefResult, FileRange,
+        InputsRequest, PingRequest, ReadDirRequest, ReadFileRequest, ResolveImageConfigRequest,
+        ReturnRequest, SolveRequest, StatFileRequest,
+    },
+    pb,
+};
+use tonic::{transport::Channel, Request};
+
+use crate::caps::{self, Capabilities};
+use crate::logging::logged;
+
+/// A ref to the result of a solve, which files can be read from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Reference(String);
+
+impl Reference {
+    pub fn new<S: Into<String>>(id: S) -> Reference {
+        Reference(id.into())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+
+    /// A frontend result made of just this ref.
+    pub fn into_result(self) -> frontend::Result {
+        frontend::Result {
+            result: Some(RefResult::RefDeprecated(self.0)),
+            metadata: Default::default(),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An image reference pinned by `Bridge::resolve_image_config`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedImage {
+    pub digest: String,
+    /// The image config, as JSON.
+    pub config: Vec<u8>,
+}
+
+/// Typed client of the BuildKit gateway. Every request is logged, see `logging::logged`.
+///
+/// Clones share the connection, and their requests are sent concurrently.
+#[derive(Debug, Clone)]
+pub struct Bridge {
+    client: LlbBridgeClient<Channel>,
+    capabilities: Capabilities,
+}
+
+impl Bridge {
+    pub fn new(client: LlbBridgeClient<Channel>) -> Bridge {
+        Bridge {
+            client,
+            capabilities: Capabilities::unknown(),
+        }
+    }
+
+    /// What BuildKit supports, once `ping` asked for it. Unknown, and so assumed to be
+    /// everything, before that.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Asks BuildKit for its capabilities, which the requests of this bridge then check before
+    /// being sent. Gateways that don't answer pings leave them unknown.
+    pub async fn ping(&mut self) -> Result<&Capabilities> {
+        let client = &mut self.client;
+        self.capabilities = match logged("Ping", Request::new(PingRequest {}), |request| {
+            client.ping(request)
+        })
+        .await
+        {
+            Ok(response) => Capabilities::from_pong(response.into_inner()),
+            Err(status) if status.code() == tonic::Code::Unimplemented => Capabilities::unknown(),
+            Err(status) => return Err(status.into()),
+        };
+        Ok(&self.capabilities)
+    }
+
+    /// The underlying gRPC client, for the requests this type doesn't cover.
+    pub fn client(&self) -> &LlbBridgeClient<Channel> {
+        &self.client
+    }
+
+    pub async fn read_file<P>(
+        &mut self,
+        reference: &Reference,
+        path: P,
+        range: Option<FileRange>,
+    ) -> Result<Vec<u8>>
+    where
+        P: Into<PathBuf>,
+    {
+        self.capabilities
+            .require(caps::CAP_READ_FILE, "reading files")?;
+        let request = ReadFileRequest {
+            r#ref: reference.id().to_string(),
+            file_path: path.into().display().to_string(),
+            range,
+        };
+        let client = &mut self.client;
+        let response = logged("ReadFile", Request::new(request), |request| {
+            client.read_file(request)
+        })
+        .await?;
+        Ok(response.into_inner().data)
+    }
+
+    /// Lists a directory, optionally only the entries matching `include_pattern`.
+    pub async fn read_dir<P>(
+        &mut self,
+        reference: &Reference,
+        path: P,
+        include_pattern: Option<&str>,
+    ) -> Result<Vec<Stat>>
+    where
+        P: Into<PathBuf>,
+    {
+        self.capabilities
+            .require(caps::CAP_READ_DIR, "listing directories")?;
+        let request = ReadDirRequest {
+            r#ref: reference.id().to_string(),
+            dir_path: path.into().display().to_string(),
+            include_pattern: include_pattern.unwrap_or_default().to_string(),
+        };
+        let client = &mut self.client;
+        let response = logged("ReadDir", Request::new(request), |request| {
+            client.read_dir(request)
+        })
+        .await?;
+        Ok(response.into_inner().entries)
+    }
+
+    /// Stats a file without following symlinks.
+    pub async fn stat_file<P>(&mut self, reference: &Reference, path: P) -> Result<Stat>
+    where
+        P: Into<PathBuf>,
+    {
+        self.capabilities
+            .require(caps::CAP_STAT_FILE, "statting files")?;
+        let path = path.into().display().to_string();
+        let request = StatFileRequest {
+            r#ref: reference.id().to_string(),
+            path: path.clone(),
+        };
+        let client = &mut self.client;
+        match logged("StatFile", Request::new(request), |request| {
+            client.stat_file(request)
+        })
+        .await?
+        .into_inner()
+        .stat
+        {
+            Some(stat) => Ok(stat),
+            None => bail!("The stat of \"{}\" is empty", path),
+        }
+    }
+
+    pub async fn solve(&mut self, graph: Terminal<'_>) -> Result<Reference> {
+        self.solve_definition(graph.into_definition()).await
+    }
+
+    /// Solves a definition that produces a single ref.
+    pub async fn solve_definition(&mut self, definition: pb::Definition) -> Result<Reference> {
+        self.capabilities.require(caps::CAP_SOLVE_BASE, "solving")?;
+        let request = SolveRequest {
+            definition: Some(definition),
+            allow_result_return: true,
+            ..Default::default()
+        };
+        let client = &mut self.client;
+        let result = logged("Solve", Request::new(request), |request| {
+            client.solve(request)
+        })
+        .await?
+        .into_inner()
+        .result
+        .and_then(|result| result.result);
+        match result {
+            Some(RefResult::RefDeprecated(id)) => Ok(Reference(id)),
+            Some(RefResult::Ref(reference)) => Ok(Reference(reference.id)),
+            Some(RefResult::RefsDeprecated(_)) | Some(RefResult::Refs(_)) => {
+                bail!("Expected a single result from the solve, but got a map of results")
+            }
+            None => bail!("The solve returned no result"),
+        }
+    }
+
+    /// Resolves an image reference, such as `alpine:3.18`, to its digest and config.
+    pub async fn resolve_image_config(&mut self, image: &str) -> Result<ResolvedImage> {
+        self.capabilities
+            .require(caps::CAP_RESOLVE_IMAGE, "resolving images")?;
+        let request = ResolveImageConfigRequest {
+            r#ref: image.to_string(),
+            ..Default::default()
+        };
+        let client = &mut self.client;
+        let response = logged("ResolveImageConfig", Request::new(request), |request| {
+            client.resolve_image_config(request)
+        })
+        .await?
+        .into_inner();
+        Ok(ResolvedImage {
+            digest: response.digest,
+            config: response.config,
+        })
+    }
+
+    /// Definitions passed to the frontend as inputs, for example by a frontend calling this one.
+    pub async fn inputs(&mut self) -> Result<HashMap<String, pb::Definition>> {
+        // BuildKit versions without frontend inputs can't have passed any.
+        if !self.capabilities.supports(caps::CAP_FRONTEND_INPUTS) {
+            return Ok(HashMap::new());
+        }
+        let client = &mut self.client;
+        match logged("Inputs", Request::new(InputsRequest {}), |request| {
+            client.inputs(request)
+        })
+        .await
+        {
+            Ok(response) => Ok(response.into_inner().definitions),
+            // Neither can those that don't answer pings, and don't know about inputs.
+            Err(status) if status.code() == tonic::Code::Unimplemented => Ok(HashMap::new()),
+            Err(status) => Err(status.into()),
+        }
+    }
+
+    /// Hands the result of the frontend, or the error it failed with, to BuildKit.
+    pub async fn return_result(&mut self, result: ReturnRequest) -> Result<()> {
+        let client = &mut self.client;
+        logged("Return", Request::new(result), |request| {
+            client.r#return(request)
+        })
+        .await?;
+        Ok(())
+    }
+}
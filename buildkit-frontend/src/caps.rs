@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use buildkit_proto::moby::buildkit::v1::{apicaps::ApiCap, frontend::PongResponse};
+
+// Capabilities of the gateway API.
+pub const CAP_SOLVE_BASE: &str = "solve.base";
+pub const CAP_RETURN_RESULT: &str = "return";
+pub const CAP_RETURN_MAP: &str = "returnmap";
+pub const CAP_RESOLVE_IMAGE: &str = "resolveimage";
+pub const CAP_READ_FILE: &str = "readfile";
+pub const CAP_READ_DIR: &str = "readdir";
+pub const CAP_STAT_FILE: &str = "statfile";
+pub const CAP_FRONTEND_INPUTS: &str = "frontend.inputs";
+
+// Capabilities of the LLB solver.
+pub const CAP_SOURCE_IMAGE: &str = "source.image";
+pub const CAP_SOURCE_LOCAL: &str = "source.local";
+pub const CAP_SOURCE_GIT: &str = "source.git";
+pub const CAP_SOURCE_HTTP: &str = "source.http";
+pub const CAP_FILE_BASE: &str = "file.base";
+
+/// What the BuildKit daemon behind the gateway supports, as reported by `Ping`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Capabilities {
+    /// Gateway and LLB capabilities by ID, which don't overlap. `None` if the gateway doesn't
+    /// report them, in which case everything is assumed to be supported.
+    caps: Option<HashMap<String, ApiCap>>,
+}
+
+impl Capabilities {
+    /// Capabilities of a gateway that doesn't report them.
+    pub fn unknown() -> Capabilities {
+        Capabilities::default()
+    }
+
+    pub fn from_pong(pong: PongResponse) -> Capabilities {
+        let caps = pong
+            .frontend_api_caps
+            .into_iter()
+            .chain(pong.llb_caps)
+            .map(|cap| (cap.id.clone(), cap))
+            .collect();
+        Capabilities { caps: Some(caps) }
+    }
+
+    pub fn supports(&self, id: &str) -> bool {
+        self.require(id, id).is_ok()
+    }
+
+    /// Fails unless capability `id`, which `feature` needs, is supported.
+    pub fn require(&self, id: &str, feature: &str) -> Result<(), Unsupported> {
+        let caps = match &self.caps {
+            Some(caps) => caps,
+            None => return Ok(()),
+        };
+        let reason = match caps.get(id) {
+            Some(cap) if cap.enabled => return Ok(()),
+            Some(cap) => Some(cap.disabled_reason_msg.clone()).filter(|msg| !msg.is_empty()),
+            None => None,
+        };
+        Err(Unsupported {
+            feature: feature.to_string(),
+            cap: id.to_string(),
+            disabled: caps.contains_key(id),
+            reason,
+        })
+    }
+}
+
+/// A feature the BuildKit daemon doesn't support.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unsupported {
+    pub feature: String,
+    pub cap: String,
+    /// Whether the daemon knows about the capability, but has it disabled.
+    pub disabled: bool,
+    /// Why the capability is disabled, if the daemon says.
+    pub reason: Option<String>,
+}
+
+impl fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.disabled {
+            return write!(
+                f,
+                "Your BuildKit is too old for {} (needs cap {})",
+                self.feature, self.cap
+            );
+        }
+        write!(
+            f,
+            "Your BuildKit has disabled {} (cap {})",
+            self.feature, self.cap
+        )?;
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+#[test]
+fn requires_reported_capabilities() {
+    let cap = |id: &str, enabled| ApiCap {
+        id: id.to_string(),
+        enabled,
+        disabled_reason_msg: if enabled {
+            String::new()
+        } else {
+            "disabled by the administrator".to_string()
+        },
+        ..Default::default()
+    };
+    let caps = Capabilities::from_pong(PongResponse {
+        frontend_api_caps: vec![cap(CAP_READ_FILE, true), cap(CAP_RETURN_MAP, false)],
+        llb_caps: vec![cap(CAP_SOURCE_GIT, true)],
+        workers: vec![],
+    });
+
+    assert!(caps.supports(CAP_READ_FILE));
+    assert!(caps.supports(CAP_SOURCE_GIT));
+    assert_eq!(
+        caps.require(CAP_SOURCE_HTTP, "http includes")
+            .unwrap_err()
+            .to_string(),
+        "Your BuildKit is too old for http includes (needs cap source.http)"
+    );
+    assert_eq!(
+        caps.require(CAP_RETURN_MAP, "multi-platform builds")
+            .unwrap_err()
+            .to_string(),
+        "Your BuildKit has disabled multi-platform builds (cap returnmap): disabled by the administrator"
+    );
+    assert!(Capabilities::unknown().supports(CAP_SOURCE_HTTP));
+}
@@ -0,0 +1,50 @@
+use anyhow::{Context, Result};
+use buildkit_proto::{google::rpc::Status, moby::buildkit::v1::frontend::ReturnRequest};
+
+use crate::bridge::Bridge;
+use crate::transport::Transport;
+
+/// A BuildKit frontend: turns its options, read from the environment, and the sources it asks
+/// the bridge for into a build result.
+#[tonic::async_trait]
+pub trait Frontend: Send + Sync {
+    async fn run(&self, bridge: Bridge) -> Result<ReturnRequest>;
+
+    /// The status BuildKit reports when `run` fails.
+    fn error_status(&self, error: &anyhow::Error) -> Status {
+        Status {
+            code: tonic::Code::Unknown as i32,
+            message: format!("{:#}", error),
+            details: vec![],
+        }
+    }
+}
+
+/// Runs `frontend` connected to the gateway set with `BUILDKIT_FRONTEND_GATEWAY` or, as BuildKit
+/// runs frontends, over stdio.
+pub async fn run<F: Frontend>(frontend: &F) -> Result<()> {
+    let client = Transport::from_env()?
+        .connect()
+        .await
+        .context("Could not connect to BuildKit")?;
+    run_with(frontend, Bridge::new(client)).await
+}
+
+/// Runs `frontend` and returns its result, or the error it failed with, to BuildKit.
+///
+/// The capabilities of BuildKit are negotiated first, so that the bridge handed to the frontend
+/// knows them.
+pub async fn run_with<F: Frontend>(frontend: &F, mut bridge: Bridge) -> Result<()> {
+    let result = match bridge.ping().await {
+        Ok(_) => frontend.run(bridge.clone()).await,
+        Err(e) => Err(e.context("Could not ask BuildKit for its capabilities")),
+    };
+    let result = result.unwrap_or_else(|e| ReturnRequest {
+        result: None,
+        error: Some(frontend.error_status(&e)),
+    });
+    bridge
+        .return_result(result)
+        .await
+        .context("Could not return the result to BuildKit")
+}
@@ -0,0 +1,25 @@
+#![deny(warnings)]
+#![deny(clippy::all)]
+
+mod bridge;
+mod frontend;
+
+/// Capabilities of the BuildKit daemon, as negotiated with `Ping`.
+pub mod caps;
+
+/// Logging of gateway requests.
+pub mod logging;
+
+/// Frontend options, as BuildKit passes them in the environment.
+pub mod options;
+
+/// Transport to the BuildKit gateway over stdin and stdout.
+pub mod stdio;
+
+/// Transports to the BuildKit gateway, chosen at runtime.
+pub mod transport;
+
+pub use crate::bridge::{Bridge, Reference, ResolvedImage};
+pub use crate::caps::{Capabilities, Unsupported};
+pub use crate::frontend::{run, run_with, Frontend};
+pub use crate::transport::Transport;
@@ -0,0 +1,81 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Instant;
+
+use log::{debug, log_enabled, trace, Level};
+use regex::Regex;
+use tonic::{Request, Response, Status};
+
+/// Placeholder for redacted values.
+const REDACTED: &str = "<redacted>";
+
+/// Hides the values of build args, and of anything that looks like a secret, in the debug
+/// output of a request or response.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    // Patterns are compiled on every call, which only happens when logs are this verbose.
+    let map_entries = Regex::new(
+        r#""(build-arg:[^"]*|[^"]*(?i:secret|token|password|passwd|credential|authorization|api.?key|private.?key)[^"]*)": "(?:[^"\\]|\\.)*""#,
+    )
+    .unwrap();
+    let fields = Regex::new(
+        r#"\b(\w*(?i:secret|token|password|passwd|credential|authorization|api.?key|private.?key)\w*): "(?:[^"\\]|\\.)*""#,
+    )
+    .unwrap();
+
+    let text = map_entries.replace_all(text, format!(r#""$1": "{}""#, REDACTED).as_str());
+    match fields.replace_all(&text, format!(r#"$1: "{}""#, REDACTED).as_str()) {
+        Cow::Borrowed(_) => text,
+        Cow::Owned(redacted) => Cow::Owned(redacted),
+    }
+}
+
+/// Sends a gateway request with `send`, logging it, its response and how long it took.
+pub async fn logged<Req, Res, F, Fut>(
+    rpc: &str,
+    request: Request<Req>,
+    send: F,
+) -> Result<Response<Res>, Status>
+where
+    Req: Debug,
+    Res: Debug,
+    F: FnOnce(Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>>,
+{
+    if log_enabled!(Level::Trace) {
+        trace!(
+            "{} request: {}",
+            rpc,
+            redact(&format!("{:?}", request.get_ref()))
+        );
+    }
+    let start = Instant::now();
+    let result = send(request).await;
+    let elapsed = start.elapsed();
+    match &result {
+        Ok(response) => {
+            debug!("{} took {:?}", rpc, elapsed);
+            if log_enabled!(Level::Trace) {
+                trace!(
+                    "{} response: {}",
+                    rpc,
+                    redact(&format!("{:?}", response.get_ref()))
+                );
+            }
+        }
+        // Frontends expect some failures, such as reading a missing .dockerignore, so they are
+        // not warnings.
+        Err(status) => debug!("{} failed after {:?}: {}", rpc, elapsed, status),
+    }
+    result
+}
+
+#[test]
+fn redacts_build_args_and_secrets() {
+    let text = r#"SolveRequest { frontend_opt: {"build-arg:NPM_TOKEN": "abc\"def", "target": "release", "registry.password": "xyz", "label:image.authors": "me"}, auth_token: "s3cr3t", hostname: "builder" }"#;
+    assert_eq!(
+        redact(text),
+        r#"SolveRequest { frontend_opt: {"build-arg:NPM_TOKEN": "<redacted>", "target": "release", "registry.password": "<redacted>", "label:image.authors": "me"}, auth_token: "<redacted>", hostname: "builder" }"#
+    );
+    assert!(matches!(redact("Ping"), Cow::Borrowed(_)));
+}
@@ -0,0 +1,1055 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::iter::empty;
+
+use anyhow::Result;
+use serde::de::value::{Error, SeqDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Error as _, IntoDeserializer, MapAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Impossible};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Prefix of the environment variables BuildKit passes the frontend options in.
+pub const FRONTEND_OPT_PREFIX: &str = "BUILDKIT_FRONTEND_OPT_";
+
+/// Name of the field collecting the options a struct doesn't declare.
+const UNKNOWN_OPTIONS: &str = "*";
+
+/// Namespace of the build args, as in `build-arg:NAME=value`.
+const BUILD_ARG_NAMESPACE: &str = "build-arg:";
+
+pub fn from_env<T, I>(pairs: I) -> Result<T>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = (String, String)>,
+{
+    let owned_pairs = pairs.into_iter().collect::<Vec<_>>();
+    let pairs = {
+        owned_pairs.iter().filter_map(|(name, value)| {
+            if name.starts_with(FRONTEND_OPT_PREFIX) {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    };
+
+    let deserializer = EnvDeserializer {
+        vals: pairs.map(|value| extract_name_and_value(value)).collect(),
+    };
+
+    Ok(T::deserialize(deserializer)?)
+}
+
+/// Turns options back into the environment variables `from_env` reads them from.
+pub fn to_env<T: Serialize>(options: &T) -> Result<Vec<(String, String)>> {
+    Ok(options
+        .serialize(OptionsSerializer)?
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, value))| {
+            (
+                format!("{}{}", FRONTEND_OPT_PREFIX, index),
+                format!("{}={}", name, value),
+            )
+        })
+        .collect())
+}
+
+/// Turns options into the `frontend_opt` map of a `SolveRequest`.
+pub fn to_frontend_opt<T: Serialize>(options: &T) -> Result<HashMap<String, String>> {
+    Ok(options.serialize(OptionsSerializer)?.into_iter().collect())
+}
+
+#[derive(Debug)]
+struct EnvDeserializer<'de> {
+    vals: Vec<(&'de str, EnvValue<'de>)>,
+}
+
+/// Value of a frontend option. How it is parsed depends on the type it is deserialized into:
+/// text only becomes a list for sequences, and is only parsed as JSON for maps and structs.
+#[derive(Debug, Clone)]
+enum EnvValue<'de> {
+    Flag,
+    Text(&'de str),
+    Map(Vec<(&'de str, EnvValue<'de>)>),
+}
+
+/// An element of a comma separated list.
+#[derive(Debug)]
+struct EnvItem(String);
+
+fn extract_name_and_value(raw_value: &str) -> (&str, EnvValue<'_>) {
+    let mut parts = raw_value.splitn(2, '=');
+    let name = parts.next().unwrap();
+
+    match parts.next() {
+        None | Some("") => (name, EnvValue::Flag),
+        Some(text) => (name, EnvValue::Text(text)),
+    }
+}
+
+/// Gathers namespaced options such as `build-arg:NAME=value` into one map per namespace, named
+/// after it (`build-arg:`). The `fields` with dotted names, which frontends use for their own
+/// options (`dockerfile-plus.lock`), may also be passed as build args, which is the only way
+/// `docker build` can set them.
+fn group<'de, I>(options: I, fields: &[&str]) -> Vec<(&'de str, EnvValue<'de>)>
+where
+    I: Iterator<Item = (&'de str, EnvValue<'de>)>,
+{
+    let mut grouped: Vec<(&str, EnvValue)> = vec![];
+    let mut own_build_args = vec![];
+
+    for (name, value) in options {
+        let end = match name.find(':') {
+            Some(end) => end + 1,
+            None => {
+                grouped.push((name, value));
+                continue;
+            }
+        };
+        let (namespace, key) = name.split_at(end);
+
+        if namespace == BUILD_ARG_NAMESPACE && key.contains('.') && fields.contains(&key) {
+            own_build_args.push((key, value.clone()));
+        }
+        match grouped.iter_mut().find(|(name, _)| *name == namespace) {
+            Some((_, EnvValue::Map(entries))) => entries.push((key, value)),
+            _ => grouped.push((namespace, EnvValue::Map(vec![(key, value)]))),
+        }
+    }
+
+    for (name, value) in own_build_args {
+        if !grouped.iter().any(|(existing, _)| *existing == name) {
+            grouped.push((name, value));
+        }
+    }
+    grouped
+}
+
+/// Splits a comma separated list. As in CSV, elements may be double quoted to contain commas,
+/// and a double quote is escaped inside quotes by doubling it: `"a,b","say ""hi"""`.
+fn split_list(text: &str) -> Result<Vec<String>, Error> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                item.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if item.is_empty() => quoted = true,
+            ',' if !quoted => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    if quoted {
+        return Err(Error::custom(format_args!(
+            "unterminated quote in list '{}'",
+            text
+        )));
+    }
+    items.push(item);
+    Ok(items)
+}
+
+fn json<'de, V: Visitor<'de>>(text: &str, visitor: V) -> Result<V::Value, Error> {
+    use serde::de::Deserializer;
+
+    serde_json::Deserializer::from_reader(Cursor::new(text))
+        .deserialize_any(visitor)
+        .map_err(Error::custom)
+}
+
+/// Map of options, which prefixes errors with the name of the option they are about.
+struct EnvMap<'de> {
+    namespace: &'de str,
+    entries: std::vec::IntoIter<(&'de str, EnvValue<'de>)>,
+    current: Option<(&'de str, EnvValue<'de>)>,
+}
+
+impl<'de> EnvMap<'de> {
+    fn new(namespace: &'de str, entries: Vec<(&'de str, EnvValue<'de>)>) -> Self {
+        EnvMap {
+            namespace,
+            entries: entries.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for EnvMap<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.entries.next() {
+            Some((name, value)) => {
+                self.current = Some((name, value));
+                seed.deserialize(name.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<S::Value, Self::Error> {
+        let (name, value) = self
+            .current
+            .take()
+            .ok_or_else(|| Error::custom("option value requested before its name"))?;
+        match value {
+            // Nested maps report the full name of the option, as in `build-arg:NAME`.
+            EnvValue::Map(entries) => {
+                seed.deserialize(EnvMapDeserializer(EnvMap::new(name, entries)))
+            }
+            value => seed.deserialize(value).map_err(|e| {
+                Error::custom(format_args!(
+                    "invalid value for option '{}{}': {}",
+                    self.namespace, name, e
+                ))
+            }),
+        }
+    }
+}
+
+/// Deserializes a map of options, such as the build args.
+struct EnvMapDeserializer<'de>(EnvMap<'de>);
+
+impl<'de> IntoDeserializer<'de, Error> for EnvValue<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for EnvItem {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for EnvDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(EnvMap::new("", group(self.vals.into_iter(), &[])))
+    }
+
+    /// Options the struct doesn't declare go into its `*` field, if it has one.
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (known, unknown): (Vec<_>, Vec<_>) = self.vals.into_iter().partition(|(name, _)| {
+            let namespace = name.find(':').map(|end| &name[..=end]);
+            !fields.contains(&UNKNOWN_OPTIONS)
+                || fields.contains(name)
+                || matches!(namespace, Some(namespace) if fields.contains(&namespace))
+        });
+
+        let mut vals = group(known.into_iter(), fields);
+        if !unknown.is_empty() {
+            vals.push((UNKNOWN_OPTIONS, EnvValue::Map(unknown)));
+        }
+        visitor.visit_map(EnvMap::new("", vals))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+impl<'de> de::Deserializer<'de> for EnvMapDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+// The approach is shamelessly borrowed from https://github.com/softprops/envy/blob/master/src/lib.rs#L113
+macro_rules! forward_parsed_values_env_value {
+    ($($ty:ident => $method:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+                where V: de::Visitor<'de>
+            {
+                match self {
+                    EnvValue::Text(contents) => EnvItem(contents.to_string()).$method(visitor),
+                    _ => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! forward_parsed_values_env_item {
+    ($($ty:ident => $method:ident,)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+                where V: de::Visitor<'de>
+            {
+                match self.0.parse::<$ty>() {
+                    Ok(val) => val.into_deserializer().$method(visitor),
+                    Err(e) => Err(de::Error::custom(format_args!("{} while parsing value '{}'", e, self.0)))
+                }
+            }
+        )*
+    }
+}
+
+impl<'de> de::Deserializer<'de> for EnvValue<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            EnvValue::Flag => visitor.visit_bool(true),
+            EnvValue::Text("true") => visitor.visit_bool(true),
+            EnvValue::Text("false") => visitor.visit_bool(false),
+            EnvValue::Text(contents) => visitor.visit_borrowed_str(contents),
+            EnvValue::Map(entries) => visitor.visit_map(EnvMap::new("", entries)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            EnvValue::Flag => visitor.visit_borrowed_str(""),
+            EnvValue::Text(contents) => visitor.visit_borrowed_str(contents),
+            EnvValue::Map(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            EnvValue::Flag => SeqDeserializer::new(empty::<EnvItem>()).deserialize_seq(visitor),
+            EnvValue::Text(contents) if contents.starts_with('[') => json(contents, visitor),
+            EnvValue::Text(contents) => {
+                SeqDeserializer::new(split_list(contents)?.into_iter().map(EnvItem))
+                    .deserialize_seq(visitor)
+            }
+            EnvValue::Map(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            EnvValue::Flag => visitor.visit_map(EnvMap::new("", vec![])),
+            EnvValue::Text(contents) => json(contents, visitor),
+            EnvValue::Map(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            // Like strings, enums set by a bare flag are empty.
+            EnvValue::Flag => IntoDeserializer::<Self::Error>::into_deserializer("")
+                .deserialize_enum(name, variants, visitor),
+            EnvValue::Text(contents) => {
+                IntoDeserializer::<Self::Error>::into_deserializer(contents)
+                    .deserialize_enum(name, variants, visitor)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            EnvValue::Flag => visitor.visit_bool(true),
+            EnvValue::Text(contents) => EnvItem(contents.to_string()).deserialize_bool(visitor),
+            EnvValue::Map(_) => self.deserialize_any(visitor),
+        }
+    }
+
+    forward_parsed_values_env_value! {
+        u8 => deserialize_u8,
+        u16 => deserialize_u16,
+        u32 => deserialize_u32,
+        u64 => deserialize_u64,
+        u128 => deserialize_u128,
+        i8 => deserialize_i8,
+        i16 => deserialize_i16,
+        i32 => deserialize_i32,
+        i64 => deserialize_i64,
+        i128 => deserialize_i128,
+        f32 => deserialize_f32,
+        f64 => deserialize_f64,
+    }
+
+    forward_to_deserialize_any! {
+        byte_buf
+        bytes
+        char
+        identifier
+        ignored_any
+        tuple
+        tuple_struct
+        unit
+        unit_struct
+    }
+}
+
+impl<'de> de::Deserializer<'de> for EnvItem {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        IntoDeserializer::<Self::Error>::into_deserializer(self.0)
+            .deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        json(&self.0, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        json(&self.0, visitor)
+    }
+
+    forward_parsed_values_env_item! {
+        bool => deserialize_bool,
+        u8 => deserialize_u8,
+        u16 => deserialize_u16,
+        u32 => deserialize_u32,
+        u64 => deserialize_u64,
+        u128 => deserialize_u128,
+        i8 => deserialize_i8,
+        i16 => deserialize_i16,
+        i32 => deserialize_i32,
+        i64 => deserialize_i64,
+        i128 => deserialize_i128,
+        f32 => deserialize_f32,
+        f64 => deserialize_f64,
+    }
+
+    forward_to_deserialize_any! {
+        byte_buf
+        bytes
+        char
+        identifier
+        ignored_any
+        newtype_struct
+        seq
+        str
+        string
+        tuple
+        tuple_struct
+        unit
+        unit_struct
+    }
+}
+
+/// Serializes a struct or a map of options into `name=value` pairs. Values are written so that
+/// `from_env` reads them back: sequences as comma separated lists, quoted where needed, and
+/// maps either as namespaced options, for fields named like `build-arg:`, or as JSON.
+struct OptionsSerializer;
+
+/// Serializes the value of the option called `name`, into zero or more `name=value` pairs.
+struct ValueSerializer {
+    name: String,
+}
+
+struct OptionsMapSerializer {
+    pairs: Vec<(String, String)>,
+    name: Option<String>,
+}
+
+struct SeqSerializer {
+    name: String,
+    items: Vec<Value>,
+}
+
+struct MapSerializer {
+    name: String,
+    entries: serde_json::Map<String, Value>,
+    key: Option<String>,
+}
+
+fn unsupported(what: &str) -> Error {
+    ser::Error::custom(format_args!(
+        "{} can't be serialized as frontend options",
+        what
+    ))
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(ser::Error::custom)
+}
+
+/// Text of a list element or of a namespaced option.
+fn value_text(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text,
+        value => value.to_string(),
+    }
+}
+
+/// Quotes a list element if it would otherwise be split, unquoted or read as JSON.
+fn quote_item(item: String) -> String {
+    if item.is_empty() || item.contains(',') || item.contains('"') || item.starts_with('[') {
+        format!("\"{}\"", item.replace('"', "\"\""))
+    } else {
+        item
+    }
+}
+
+impl ser::Serializer for OptionsSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<Self::Ok, Error>;
+    type SerializeTuple = Impossible<Self::Ok, Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = OptionsMapSerializer;
+    type SerializeStruct = OptionsMapSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(OptionsMapSerializer {
+            pairs: vec![],
+            name: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(None)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, Error> {
+        Err(unsupported("A bool"))
+    }
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_u8(self, _: u8) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_u16(self, _: u16) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_u32(self, _: u32) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, Error> {
+        Err(unsupported("A number"))
+    }
+    fn serialize_char(self, _: char) -> Result<Self::Ok, Error> {
+        Err(unsupported("A char"))
+    }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, Error> {
+        Err(unsupported("A string"))
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Error> {
+        Err(unsupported("Bytes"))
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        Err(unsupported("An enum"))
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Error> {
+        Err(unsupported("An enum"))
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(unsupported("A sequence"))
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(unsupported("A tuple"))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(unsupported("A tuple"))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported("An enum"))
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported("An enum"))
+    }
+}
+
+impl ser::SerializeMap for OptionsMapSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match to_json(key)? {
+            Value::String(name) => {
+                self.name = Some(name);
+                Ok(())
+            }
+            _ => Err(unsupported("An option name that isn't a string")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let name = self.name.take().unwrap_or_default();
+        self.pairs
+            .extend(value.serialize(ValueSerializer { name })?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.pairs)
+    }
+}
+
+impl ser::SerializeStruct for OptionsMapSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let name = name.to_string();
+        self.pairs
+            .extend(value.serialize(ValueSerializer { name })?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.pairs)
+    }
+}
+
+impl ValueSerializer {
+    fn text(self, text: String) -> Result<Vec<(String, String)>, Error> {
+        Ok(vec![(self.name, text)])
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    fn serialize_bool(self, value: bool) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_i8(self, value: i8) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_i16(self, value: i16) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_i32(self, value: i32) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_i64(self, value: i64) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_u8(self, value: u8) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_u16(self, value: u16) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_u32(self, value: u32) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_u64(self, value: u64) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_f32(self, value: f32) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_f64(self, value: f64) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_char(self, value: char) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_str(self, value: &str) -> Result<Self::Ok, Error> {
+        self.text(value.to_string())
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Error> {
+        Err(unsupported("Bytes"))
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, Error> {
+        Ok(vec![])
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.text(variant.to_string())
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Error> {
+        Err(unsupported("An enum with data"))
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(SeqSerializer {
+            name: self.name,
+            items: vec![],
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(unsupported("An enum with data"))
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(MapSerializer {
+            name: self.name,
+            entries: serde_json::Map::new(),
+            key: None,
+        })
+    }
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(unsupported("An enum with data"))
+    }
+}
+
+impl SeqSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(to_json(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<(String, String)>, Error> {
+        let items = self
+            .items
+            .into_iter()
+            .map(|item| quote_item(value_text(item)))
+            .collect::<Vec<_>>();
+        // A single empty element is quoted, so an empty list is the only one written as nothing.
+        Ok(vec![(self.name, items.join(","))])
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl MapSerializer {
+    fn finish(self) -> Result<Vec<(String, String)>, Error> {
+        let prefix = if self.name == UNKNOWN_OPTIONS {
+            ""
+        } else if self.name.ends_with(':') {
+            &self.name
+        } else {
+            let json = Value::Object(self.entries).to_string();
+            return Ok(vec![(self.name, json)]);
+        };
+
+        Ok(self
+            .entries
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (format!("{}{}", prefix, key), value_text(value)))
+            .collect())
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(value_text(to_json(key)?));
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().unwrap_or_default();
+        self.entries.insert(key, to_json(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Vec<(String, String)>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entries.insert(name.to_string(), to_json(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq, serde::Deserialize, Serialize)]
+struct ExampleOptions {
+    target: Option<String>,
+    #[serde(rename = "build-arg:", default)]
+    build_args: HashMap<String, String>,
+    #[serde(rename = "example.verbose", default)]
+    verbose: bool,
+    #[serde(rename = "example.tags")]
+    tags: Option<Vec<String>>,
+    #[serde(rename = "*", default)]
+    unknown: std::collections::BTreeMap<String, String>,
+}
+
+#[test]
+fn parses_options_of_any_frontend() {
+    let env = vec![
+        ("BUILDKIT_FRONTEND_OPT_0", "target=release"),
+        (
+            "BUILDKIT_FRONTEND_OPT_1",
+            "build-arg:example.tags=a,\"b,c\"",
+        ),
+        ("BUILDKIT_FRONTEND_OPT_2", "build-arg:other.option=1"),
+        ("BUILDKIT_FRONTEND_OPT_3", "example.verbose"),
+        ("BUILDKIT_FRONTEND_OPT_4", "attest:sbom=true"),
+        ("PATH", "/bin"),
+    ];
+    let options: ExampleOptions = from_env(
+        env.into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string())),
+    )
+    .unwrap();
+
+    assert_eq!(options.target.as_deref(), Some("release"));
+    assert!(options.verbose);
+    // Declared options with dotted names may be passed as build args, other build args stay so.
+    assert_eq!(options.tags, Some(vec!["a".to_string(), "b,c".to_string()]));
+    assert_eq!(options.build_args["other.option"], "1");
+    assert!(!options.unknown.contains_key("other.option"));
+    assert_eq!(options.unknown["attest:sbom"], "true");
+
+    let parsed: ExampleOptions = from_env(to_env(&options).unwrap()).unwrap();
+    assert_eq!(parsed, options);
+    let frontend_opt = to_frontend_opt(&options).unwrap();
+    assert_eq!(frontend_opt["example.verbose"], "true");
+    assert_eq!(frontend_opt["build-arg:other.option"], "1");
+}
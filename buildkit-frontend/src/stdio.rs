@@ -1,15 +1,22 @@
 use std::io::{self, stdin, stdout, Read, Write};
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 
+use buildkit_proto::moby::buildkit::v1::frontend::{
+    llb_bridge_client::LlbBridgeClient,
+    llb_bridge_server::{LlbBridge, LlbBridgeServer},
+};
 use pin_project::pin_project;
 use std::{
     io::{Stdin, Stdout},
     net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 use tokio::io::*;
-use tonic::transport::{server::Connected, Uri};
+use tonic::transport::{server::Connected, Channel, Endpoint, Server, Uri};
+use tower::service_fn;
 
 #[pin_project]
 pub struct StdioSocket<R: Read + AsRawFd, W: Write + AsRawFd> {
@@ -24,6 +31,42 @@ pub async fn stdio_connector(_: Uri) -> io::Result<StdioSocket<Stdin, Stdout>> {
     StdioSocket::try_new()
 }
 
+/// Connects to the BuildKit gateway over stdin and stdout, as BuildKit runs frontends.
+pub async fn connect() -> anyhow::Result<LlbBridgeClient<Channel>> {
+    let channel = Endpoint::from_static("http://[::]:50051")
+        .connect_with_connector(service_fn(stdio_connector))
+        .await?;
+    Ok(LlbBridgeClient::new(channel))
+}
+
+/// Serves `bridge` in this process, and returns a client connected to it over a socket pair.
+pub async fn connect_in_process<B: LlbBridge>(
+    bridge: B,
+) -> anyhow::Result<LlbBridgeClient<Channel>> {
+    let (server_end, client_end) = UnixStream::pair()?;
+    let server_socket = StdioSocket::try_new_rw(server_end.try_clone()?, server_end);
+    tokio::spawn(
+        Server::builder()
+            .add_service(LlbBridgeServer::new(bridge))
+            .serve_with_incoming(tokio::stream::once(server_socket)),
+    );
+
+    // The connector can only hand out the one end of the pair it has.
+    let client_end = Arc::new(Mutex::new(Some(client_end)));
+    let channel = Endpoint::from_static("http://[::]:50051")
+        .connect_with_connector(service_fn(move |_| {
+            let stream = client_end.lock().unwrap().take();
+            async move {
+                let stream = stream.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotConnected, "the bridge was disconnected")
+                })?;
+                StdioSocket::try_new_rw(stream.try_clone()?, stream)
+            }
+        }))
+        .await?;
+    Ok(LlbBridgeClient::new(channel))
+}
+
 impl StdioSocket<Stdin, Stdout> {
     pub fn try_new() -> io::Result<Self> {
         Self::try_new_rw(stdin(), stdout())
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{bail, Context, Error, Result};
+use buildkit_proto::moby::buildkit::v1::frontend::{
+    llb_bridge_client::LlbBridgeClient,
+    llb_bridge_server::{LlbBridge, LlbBridgeServer},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::stream::StreamExt;
+use tonic::transport::{server::Connected, Channel, Endpoint, Server, Uri};
+use tower::service_fn;
+
+use crate::stdio::{self, StdioSocket};
+
+/// Environment variable with the address of the BuildKit gateway. Frontends use stdio, as
+/// BuildKit runs them, when it isn't set.
+pub const GATEWAY_ENV: &str = "BUILDKIT_FRONTEND_GATEWAY";
+
+/// How a frontend and the BuildKit gateway talk to each other.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// stdin and stdout, written `stdio`.
+    Stdio,
+    /// A Unix socket, written `unix:///run/gateway.sock`.
+    Unix(PathBuf),
+    /// A TCP socket, written `tcp://localhost:50051`.
+    Tcp(String),
+}
+
+impl Transport {
+    /// The transport set with `BUILDKIT_FRONTEND_GATEWAY`, or stdio.
+    pub fn from_env() -> Result<Transport> {
+        match std::env::var(GATEWAY_ENV) {
+            Ok(address) => address
+                .parse()
+                .with_context(|| format!("Invalid {}", GATEWAY_ENV)),
+            Err(_) => Ok(Transport::Stdio),
+        }
+    }
+
+    /// Connects to a gateway served at this address.
+    pub async fn connect(&self) -> Result<LlbBridgeClient<Channel>> {
+        let channel = match self {
+            Transport::Stdio => return stdio::connect().await,
+            Transport::Unix(path) => {
+                let path = path.clone();
+                // The URI is required, but it is the connector that decides where to connect.
+                Endpoint::from_static("http://[::]:50051")
+                    .connect_with_connector(service_fn(move |_: Uri| {
+                        UnixStream::connect(path.clone())
+                    }))
+                    .await
+            }
+            Transport::Tcp(address) => {
+                Endpoint::from_shared(format!("http://{}", address))?
+                    .connect()
+                    .await
+            }
+        };
+        Ok(LlbBridgeClient::new(channel.with_context(|| {
+            format!("Could not connect to {}", self)
+        })?))
+    }
+
+    /// Serves `bridge` at this address, for frontends to connect to, until the connection
+    /// over stdio is closed or, for sockets, forever.
+    pub async fn serve<B: LlbBridge>(&self, bridge: B) -> Result<()> {
+        let server = Server::builder().add_service(LlbBridgeServer::new(bridge));
+        let served = match self {
+            Transport::Stdio => {
+                let socket = StdioSocket::try_new()?;
+                server
+                    .serve_with_incoming(tokio::stream::once(Ok::<_, io::Error>(socket)))
+                    .await
+            }
+            Transport::Unix(path) => {
+                let mut listener = UnixListener::bind(path)
+                    .with_context(|| format!("Could not listen on {}", self))?;
+                server
+                    .serve_with_incoming(listener.incoming().map(|stream| stream.map(UnixSocket)))
+                    .await
+            }
+            Transport::Tcp(address) => {
+                let mut listener = TcpListener::bind(address.as_str())
+                    .await
+                    .with_context(|| format!("Could not listen on {}", self))?;
+                server.serve_with_incoming(listener.incoming()).await
+            }
+        };
+        served.with_context(|| format!("Could not serve the gateway on {}", self))
+    }
+}
+
+impl FromStr for Transport {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Transport> {
+        if address == "stdio" {
+            return Ok(Transport::Stdio);
+        }
+        match address.splitn(2, "://").collect::<Vec<_>>()[..] {
+            ["unix", path] if !path.is_empty() => Ok(Transport::Unix(PathBuf::from(path))),
+            ["tcp", address] if !address.is_empty() => Ok(Transport::Tcp(address.to_string())),
+            _ => bail!(
+                "\"{}\" is not a gateway address. Expected stdio, unix://<path> or tcp://<host>:<port>",
+                address
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Stdio => f.write_str("stdio"),
+            Transport::Unix(path) => write!(f, "unix://{}", path.display()),
+            Transport::Tcp(address) => write!(f, "tcp://{}", address),
+        }
+    }
+}
+
+/// A Unix socket connection, as served by tonic.
+struct UnixSocket(UnixStream);
+
+impl Connected for UnixSocket {}
+
+impl AsyncRead for UnixSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn parses_gateway_addresses() {
+    for address in &["stdio", "unix:///run/gateway.sock", "tcp://localhost:50051"] {
+        assert_eq!(address.parse::<Transport>().unwrap().to_string(), *address);
+    }
+    assert_eq!(
+        "unix://gateway.sock".parse::<Transport>().unwrap(),
+        Transport::Unix(PathBuf::from("gateway.sock"))
+    );
+    for address in &["", "localhost:50051", "unix://", "http://localhost"] {
+        assert!(address.parse::<Transport>().is_err(), "{}", address);
+    }
+}
@@ -12,6 +12,7 @@ pub struct GitSource {
     id: OperationId,
     remote: String,
     reference: Option<String>,
+    keep_git_dir: bool,
     description: HashMap<String, String>,
     ignore_cache: bool,
 }
@@ -38,6 +39,7 @@ impl GitSource {
             id: OperationId::default(),
             remote,
             reference: None,
+            keep_git_dir: false,
             description: Default::default(),
             ignore_cache: false,
         }
@@ -52,6 +54,12 @@ impl GitSource {
         self.reference = Some(reference.into());
         self
     }
+
+    /// Keeps the `.git` directory in the checkout, for example to read the commit it is at.
+    pub fn with_keep_git_dir(mut self, keep: bool) -> Self {
+        self.keep_git_dir = keep;
+        self
+    }
 }
 
 impl<'a> SingleBorrowedOutput<'a> for GitSource {
@@ -95,11 +103,13 @@ impl Operation for GitSource {
             format!("git://{}", self.remote)
         };
 
+        let mut attrs = HashMap::default();
+        if self.keep_git_dir {
+            attrs.insert("git.keepgitdir".into(), "true".into());
+        }
+
         let head = pb::Op {
-            op: Some(Op::Source(SourceOp {
-                identifier,
-                attrs: Default::default(),
-            })),
+            op: Some(Op::Source(SourceOp { identifier, attrs })),
 
             ..Default::default()
         };
@@ -228,3 +238,23 @@ fn with_reference() {
         },
     );
 }
+
+#[test]
+fn with_keep_git_dir() {
+    crate::check_op!(
+        GitSource::new("any.url").with_keep_git_dir(true),
+        |digest| { "sha256:35b471cabe97440e7a6a445aef910574a65d9e9dce8ae8610da862301f775f90" },
+        |description| { vec![] },
+        |caps| { vec![] },
+        |cached_tail| { vec![] },
+        |inputs| { vec![] },
+        |op| {
+            Op::Source(SourceOp {
+                identifier: "git://any.url".into(),
+                attrs: vec![("git.keepgitdir".to_string(), "true".to_string())]
+                    .into_iter()
+                    .collect(),
+            })
+        },
+    );
+}
@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use buildkit_frontend::{Bridge, Reference};
+
+use crate::error::{is_not_found, Error, ErrorKind};
+
+/// `os.ModeSymlink` from Go, as reported in `fsutil.types.Stat.mode`.
+const MODE_SYMLINK: u32 = 1 << 27;
+
+/// Same limit Linux uses before failing with `ELOOP`.
+const MAX_SYMLINKS: usize = 40;
+
+fn blocked<R: Into<String>>(path: &str, reason: R) -> anyhow::Error {
+    Error::new(
+        ErrorKind::Blocked,
+        format!("INCLUDE+ path \"{}\" was blocked: {}", path, reason.into()),
+    )
+    .into()
+}
+
+/// Resolves an include path against the build context, following symlinks, and fails if
+/// the path or any symlink along the way points outside of the context root.
+///
+/// The returned path is normalized and relative to the context root.
+pub async fn resolve_in_context(
+    bridge: &mut Bridge,
+    context_layer: &Reference,
+    path: &str,
+) -> Result<String> {
+    if path.starts_with('/') {
+        return Err(blocked(
+            path,
+            "absolute paths are not allowed, include paths must be relative to the build context",
+        ));
+    }
+
+    let mut pending: VecDeque<String> = path.split('/').map(str::to_string).collect();
+    let mut resolved: Vec<String> = vec![];
+    let mut last_link: Option<(String, String)> = None;
+    let mut links = 0;
+    let mut exists = true;
+
+    while let Some(component) = pending.pop_front() {
+        match component.as_str() {
+            "" | "." => continue,
+            ".." => {
+                if resolved.pop().is_none() {
+                    match last_link {
+                        Some((link, target)) => {
+                            return Err(blocked(
+                                path,
+                                format!(
+                                    "symlink \"{}\" points to \"{}\", which is outside the build context",
+                                    link, target
+                                ),
+                            ))
+                        }
+                        None => return Err(blocked(path, "it points outside the build context")),
+                    }
+                }
+                continue;
+            }
+            _ => resolved.push(component),
+        }
+
+        // Once a component is missing there's nothing left to stat. Keep normalizing so
+        // traversal is still caught, and let the read fail with the usual error.
+        if !exists {
+            continue;
+        }
+
+        let current = resolved.join("/");
+        let stat = match bridge.stat_file(context_layer, &current).await {
+            Ok(stat) => stat,
+            Err(e) if is_not_found(&e) => {
+                exists = false;
+                continue;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Could not stat \"{}\" in the build context",
+                    current
+                )))
+            }
+        };
+
+        if stat.mode & MODE_SYMLINK == 0 {
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(blocked(path, "too many levels of symlinks"));
+        }
+        if stat.linkname.starts_with('/') {
+            return Err(blocked(
+                path,
+                format!(
+                    "symlink \"{}\" points to the absolute path \"{}\", which is outside the build context",
+                    current, stat.linkname
+                ),
+            ));
+        }
+
+        resolved.pop();
+        for target_component in stat.linkname.rsplit('/') {
+            pending.push_front(target_component.to_string());
+        }
+        last_link = Some((current, stat.linkname));
+    }
+
+    if resolved.is_empty() {
+        return Err(blocked(
+            path,
+            "it points to the root of the build context, not to a file",
+        ));
+    }
+
+    Ok(resolved.join("/"))
+}
+
+#[cfg(test)]
+async fn resolve(bridge: crate::mock_bridge::MockBridge, path: &str) -> Result<String> {
+    let mut bridge = Bridge::new(bridge.connect().await);
+    bridge.ping().await?;
+    resolve_in_context(&mut bridge, &Reference::new("context"), path).await
+}
+
+#[tokio::test]
+async fn follows_symlinks_inside_the_context() {
+    use crate::mock_bridge::MockBridge;
+
+    let bridge = MockBridge::default()
+        .with_file("docker/common.Dockerfile", "RUN echo common")
+        .with_symlink("current", "docker/./latest")
+        .with_symlink("docker/latest", "../docker/common.Dockerfile");
+    assert_eq!(
+        resolve(bridge, "./current").await.unwrap(),
+        "docker/common.Dockerfile"
+    );
+
+    // Missing files resolve to their normalized path, and fail when read.
+    let bridge = MockBridge::default();
+    assert_eq!(
+        resolve(bridge, "docker/../missing.Dockerfile")
+            .await
+            .unwrap(),
+        "missing.Dockerfile"
+    );
+}
+
+#[tokio::test]
+async fn blocks_paths_leaving_the_context() {
+    use crate::mock_bridge::MockBridge;
+
+    let blocked = |bridge: MockBridge, path: &'static str, reason: &'static str| async move {
+        let error = resolve(bridge, path).await.unwrap_err();
+        let typed = error.downcast_ref::<Error>().unwrap();
+        assert_eq!(typed.kind, ErrorKind::Blocked);
+        assert!(typed.message.ends_with(reason), "{}", typed.message);
+    };
+
+    blocked(
+        MockBridge::default(),
+        "docker/../../secrets",
+        "it points outside the build context",
+    )
+    .await;
+    blocked(
+        MockBridge::default(),
+        "/etc/passwd",
+        "absolute paths are not allowed, include paths must be relative to the build context",
+    )
+    .await;
+    blocked(
+        MockBridge::default().with_symlink("escape", "../secrets/Dockerfile"),
+        "escape",
+        "symlink \"escape\" points to \"../secrets/Dockerfile\", which is outside the build context",
+    )
+    .await;
+    blocked(
+        MockBridge::default().with_symlink("docker/passwd", "/etc/passwd"),
+        "docker/passwd",
+        "symlink \"docker/passwd\" points to the absolute path \"/etc/passwd\", which is outside the build context",
+    )
+    .await;
+    blocked(
+        MockBridge::default()
+            .with_symlink("ping", "pong")
+            .with_symlink("pong", "ping"),
+        "ping",
+        "too many levels of symlinks",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn reports_stat_failures() {
+    use crate::mock_bridge::MockBridge;
+
+    // Failures other than a missing file are not mistaken for one.
+    let bridge = MockBridge {
+        capabilities: Some(vec!["readfile".to_string()]),
+        ..Default::default()
+    }
+    .with_file("common.Dockerfile", "RUN echo common");
+    let error = resolve(bridge, "common.Dockerfile").await.unwrap_err();
+    assert!(format!("{:#}", error).starts_with(
+        "Could not stat \"common.Dockerfile\" in the build context: Your BuildKit is too old"
+    ));
+}
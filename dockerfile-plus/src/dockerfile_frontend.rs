@@ -1,56 +1,283 @@
-use std::{process::Stdio, sync::Arc};
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
 
-use crate::stdio::StdioSocket;
-use anyhow::Result;
+use crate::error::{classify, Error, ErrorKind};
+use crate::options::DockerfileOptions;
+use anyhow::{bail, Context, Result};
+use buildkit_frontend::logging::logged;
+use buildkit_frontend::options::{self, FRONTEND_OPT_PREFIX};
+use buildkit_frontend::stdio::StdioSocket;
 use buildkit_proto::moby::buildkit::v1::frontend::{
     self, llb_bridge_client::LlbBridgeClient, llb_bridge_server::LlbBridge,
 };
-use crossbeam::{channel, Sender};
+use crossbeam::{channel, channel::TrySendError, Sender};
 use frontend::{llb_bridge_server::LlbBridgeServer, ReadFileResponse};
-use tokio::sync::RwLock;
-use tonic::{transport::Channel, transport::Server, Request, Response};
+use log::{debug, info, trace, warn};
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::time::{timeout_at, Instant};
+use tonic::{
+    transport::{Channel, Server},
+    Request, Response,
+};
+
+/// The Dockerfile frontend, as shipped in the `docker/dockerfile` image this one is built on.
+const DOCKERFILE_FRONTEND_PATH: &str = "/bin/dockerfile-frontend";
+
+/// How long the Dockerfile frontend may take, build included, unless
+/// `dockerfile-plus.frontend-timeout` says otherwise.
+const DEFAULT_FRONTEND_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How much of the end of the stderr of the Dockerfile frontend is kept for error messages.
+const STDERR_TAIL_SIZE: usize = 4096;
+
+/// Stands in for the Dockerfile frontend in tests, answering the options it is called with.
+#[cfg(test)]
+pub type FakeFrontend = fn(&DockerfileOptions) -> frontend::Result;
 
 pub struct DockerfileFrontend {
     client: LlbBridgeClient<Channel>,
     dockerfile_name: String,
+    options: DockerfileOptions,
+    program: String,
+    args: Vec<String>,
+    #[cfg(test)]
+    fake: Option<FakeFrontend>,
 }
 
 impl DockerfileFrontend {
-    pub fn new(client: LlbBridgeClient<Channel>, dockerfile_name: &str) -> DockerfileFrontend {
+    pub fn new(
+        client: LlbBridgeClient<Channel>,
+        dockerfile_name: &str,
+        options: DockerfileOptions,
+    ) -> DockerfileFrontend {
         DockerfileFrontend {
             client,
             dockerfile_name: dockerfile_name.to_string(),
+            options,
+            program: DOCKERFILE_FRONTEND_PATH.to_string(),
+            args: vec![],
+            #[cfg(test)]
+            fake: None,
         }
     }
 
+    /// Runs `program` instead of the Dockerfile frontend.
+    #[cfg(test)]
+    fn with_command(mut self, program: &str, args: &[&str]) -> DockerfileFrontend {
+        self.program = program.to_string();
+        self.args = args.iter().map(|arg| arg.to_string()).collect();
+        self
+    }
+
+    /// Returns the results of `fake`, through the proxy, instead of running a process.
+    #[cfg(test)]
+    pub fn with_fake(mut self, fake: FakeFrontend) -> DockerfileFrontend {
+        self.fake = Some(fake);
+        self
+    }
+
+    pub fn dockerfile_name(&self) -> &str {
+        &self.dockerfile_name
+    }
+
+    /// Options this frontend was called with, which are forwarded to the Dockerfile frontend.
+    pub fn options(&self) -> &DockerfileOptions {
+        &self.options
+    }
+
     pub async fn solve(&self, dockerfile_contents: &str) -> Result<frontend::ReturnRequest> {
-        let mut dockerfile_front = std::process::Command::new("/bin/dockerfile-frontend")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .envs(std::env::vars())
-            .spawn()?;
+        self.solve_with_options(dockerfile_contents, &self.options)
+            .await
+    }
+
+    /// Solves the Dockerfile with a modified set of frontend options.
+    pub async fn solve_with_options(
+        &self,
+        dockerfile_contents: &str,
+        options: &DockerfileOptions,
+    ) -> Result<frontend::ReturnRequest> {
+        info!("Building the expanded Dockerfile with the Dockerfile frontend");
+        let start = std::time::Instant::now();
+        let result = self
+            .run_frontend(dockerfile_contents, options)
+            .await
+            .map_err(|e| classify(e, ErrorKind::Frontend));
+        info!("The Dockerfile frontend finished in {:?}", start.elapsed());
+        result
+    }
+
+    /// Runs the Dockerfile frontend as a child process, proxying its gateway requests to
+    /// BuildKit. The child is killed if it outlives the timeout, and is always reaped.
+    async fn run_frontend(
+        &self,
+        dockerfile_contents: &str,
+        options: &DockerfileOptions,
+    ) -> Result<frontend::ReturnRequest> {
+        #[cfg(test)]
+        if let Some(fake) = self.fake {
+            return self.run_fake(fake, dockerfile_contents, options).await;
+        }
+
+        let timeout = options
+            .frontend_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_FRONTEND_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+
+        // The Dockerfile frontend talks to the gateway over its stdin and stdout, which are both
+        // the other end of the socket served here.
+        let (ours, theirs) =
+            UnixStream::pair().context("Could not create the Dockerfile frontend socket")?;
+        let (stdin, stdout) = unsafe {
+            (
+                Stdio::from_raw_fd(theirs.try_clone()?.into_raw_fd()),
+                Stdio::from_raw_fd(theirs.into_raw_fd()),
+            )
+        };
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .env_clear()
+            .envs(std::env::vars().filter(|(name, _)| !name.starts_with(FRONTEND_OPT_PREFIX)))
+            .envs(options::to_env(options)?)
+            .kill_on_drop(true)
+            .spawn()
+            .context("Could not start the Dockerfile frontend")?;
+
+        let socket = StdioSocket::try_new_rw(ours.try_clone()?, ours)?;
+        let stderr_tail = match child.stderr.take() {
+            Some(stderr) => tokio::spawn(forward_stderr(stderr)),
+            None => bail!("The Dockerfile frontend was started without a stderr pipe"),
+        };
 
         let (tx, rx) = channel::bounded(1);
-        Server::builder()
+        // Serving returns as soon as the connection is accepted, which is then served in the
+        // background until the child closes it, or dies.
+        let served = Server::builder()
             .add_service(LlbBridgeServer::new(ProxyLlbServer::new(
                 self.client.clone(),
                 tx,
                 self.dockerfile_name.clone(),
                 dockerfile_contents.as_bytes().to_vec(),
             )))
-            .serve_with_incoming(tokio::stream::once(StdioSocket::try_new_rw(
-                dockerfile_front.stdout.take().unwrap(),
-                dockerfile_front.stdin.take().unwrap(),
-            )))
+            .serve_with_incoming(tokio::stream::once(Ok::<_, io::Error>(socket)))
+            .await;
+        if let Err(e) = served {
+            wait_or_kill(&mut child, Instant::now()).await?;
+            return Err(e).context("Could not serve the Dockerfile frontend");
+        }
+
+        let (status, killed) = wait_or_kill(&mut child, deadline).await?;
+        let stderr_tail = stderr_tail.await.unwrap_or_default();
+
+        if let Ok(result) = rx.try_recv() {
+            if !status.success() {
+                warn!(
+                    "The Dockerfile frontend returned a result, but then exited with {}",
+                    status
+                );
+            }
+            return Ok(result);
+        }
+        let output = match stderr_tail.is_empty() {
+            true => String::new(),
+            false => format!("\nLast output of the Dockerfile frontend:\n{}", stderr_tail),
+        };
+        if killed {
+            bail!(Error::new(
+                ErrorKind::Timeout,
+                format!(
+                    "The Dockerfile frontend did not finish within {:?} (set dockerfile-plus.frontend-timeout to raise the limit){}",
+                    timeout, output
+                ),
+            ));
+        }
+        bail!(
+            "The Dockerfile frontend exited without returning a result ({}){}",
+            status,
+            output
+        )
+    }
+}
+
+#[cfg(test)]
+impl DockerfileFrontend {
+    async fn run_fake(
+        &self,
+        fake: FakeFrontend,
+        dockerfile_contents: &str,
+        options: &DockerfileOptions,
+    ) -> Result<frontend::ReturnRequest> {
+        let (tx, rx) = channel::bounded(1);
+        let proxy = ProxyLlbServer::new(
+            self.client.clone(),
+            tx,
+            self.dockerfile_name.clone(),
+            dockerfile_contents.as_bytes().to_vec(),
+        );
+        let mut bridge = buildkit_frontend::Bridge::new(
+            buildkit_frontend::stdio::connect_in_process(proxy).await?,
+        );
+        bridge
+            .return_result(frontend::ReturnRequest {
+                result: Some(fake(options)),
+                error: None,
+            })
             .await?;
+        Ok(rx.try_recv()?)
+    }
+}
 
-        dockerfile_front.wait()?;
-        Ok(rx.recv()?)
+/// Waits for `child` to exit until `deadline`, then kills it. Returns its exit status and
+/// whether it was killed.
+async fn wait_or_kill(child: &mut Child, deadline: Instant) -> Result<(ExitStatus, bool)> {
+    if let Ok(status) = timeout_at(deadline, &mut *child).await {
+        return Ok((status?, false));
     }
+    debug!("Killing the Dockerfile frontend");
+    // Killing fails if the child exited in the meantime, which is just as good.
+    let _ = child.kill();
+    let status = child
+        .await
+        .context("Could not wait for the Dockerfile frontend to exit")?;
+    Ok((status, true))
+}
+
+/// Copies the stderr of the Dockerfile frontend to ours, so it still shows in the build
+/// output, and returns its last lines.
+async fn forward_stderr(mut stderr: ChildStderr) -> String {
+    let mut tail = vec![];
+    let mut buffer = [0; 4096];
+    while let Ok(read) = stderr.read(&mut buffer).await {
+        if read == 0 {
+            break;
+        }
+        let _ = io::stderr().write_all(&buffer[..read]);
+        tail.extend_from_slice(&buffer[..read]);
+        if tail.len() > STDERR_TAIL_SIZE {
+            tail.drain(..tail.len() - STDERR_TAIL_SIZE);
+        }
+    }
+
+    let tail = String::from_utf8_lossy(&tail);
+    // Only whole lines, unless a single line fills the tail.
+    let tail = match tail.trim_end().find('\n') {
+        Some(start) if tail.len() >= STDERR_TAIL_SIZE => &tail[start + 1..],
+        _ => &tail,
+    };
+    tail.trim_end().to_string()
 }
 
 struct ProxyLlbServer {
-    client: Arc<RwLock<LlbBridgeClient<Channel>>>,
+    /// Cloned for every request, so that requests of the Dockerfile frontend are forwarded
+    /// concurrently over the same connection.
+    client: LlbBridgeClient<Channel>,
     result_sender: Sender<frontend::ReturnRequest>,
 
     dockerfile_name: String,
@@ -65,7 +292,7 @@ impl ProxyLlbServer {
         dockerfile_contents: Vec<u8>,
     ) -> Self {
         ProxyLlbServer {
-            client: Arc::new(RwLock::new(client)),
+            client,
             result_sender,
             dockerfile_name,
             dockerfile_contents,
@@ -79,78 +306,77 @@ impl LlbBridge for ProxyLlbServer {
         &self,
         request: Request<frontend::ResolveImageConfigRequest>,
     ) -> Result<Response<frontend::ResolveImageConfigResponse>, tonic::Status> {
-        eprintln!("Resolve image config: {:?}", request);
-        let result = self
-            .client
-            .write()
-            .await
-            .resolve_image_config(request)
-            .await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("ResolveImageConfig", request, |request| async move {
+            client.resolve_image_config(request).await
+        })
+        .await
     }
 
     async fn solve(
         &self,
         request: Request<frontend::SolveRequest>,
     ) -> Result<Response<frontend::SolveResponse>, tonic::Status> {
-        eprintln!("Solve: {:?}", request);
-        let result = self.client.write().await.solve(request).await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("Solve", request, |request| async move {
+            client.solve(request).await
+        })
+        .await
     }
 
     async fn read_file(
         &self,
         request: Request<frontend::ReadFileRequest>,
     ) -> Result<Response<frontend::ReadFileResponse>, tonic::Status> {
-        eprintln!("Read file: {:?}", request);
-        let inner = request.into_inner();
-        let request = Request::new(inner.clone());
-        let result = if inner.file_path == self.dockerfile_name {
-            eprintln!("ITS A TRAP!");
-            eprintln!(
-                "{}",
-                std::str::from_utf8(&self.dockerfile_contents).unwrap()
+        if request.get_ref().file_path == self.dockerfile_name {
+            // Serve the expanded Dockerfile instead of the one in the build context.
+            debug!(
+                "ReadFile {}: serving the expanded Dockerfile",
+                self.dockerfile_name
             );
-            Ok(Response::new(ReadFileResponse {
+            trace!("{}", String::from_utf8_lossy(&self.dockerfile_contents));
+            return Ok(Response::new(ReadFileResponse {
                 data: self.dockerfile_contents.clone(),
-            }))
-        } else {
-            self.client.write().await.read_file(request).await
-        };
-        eprintln!("{:?}", result);
-        result
+            }));
+        }
+        let mut client = self.client.clone();
+        logged("ReadFile", request, |request| async move {
+            client.read_file(request).await
+        })
+        .await
     }
 
     async fn read_dir(
         &self,
         request: Request<frontend::ReadDirRequest>,
     ) -> Result<Response<frontend::ReadDirResponse>, tonic::Status> {
-        eprintln!("Read dir: {:?}", request);
-        let result = self.client.write().await.read_dir(request).await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("ReadDir", request, |request| async move {
+            client.read_dir(request).await
+        })
+        .await
     }
 
     async fn stat_file(
         &self,
         request: Request<frontend::StatFileRequest>,
     ) -> Result<Response<frontend::StatFileResponse>, tonic::Status> {
-        eprintln!("Stat file: {:?}", request);
-        let result = self.client.write().await.stat_file(request).await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("StatFile", request, |request| async move {
+            client.stat_file(request).await
+        })
+        .await
     }
 
     async fn ping(
         &self,
         request: Request<frontend::PingRequest>,
     ) -> Result<Response<frontend::PongResponse>, tonic::Status> {
-        eprintln!("Ping: {:?}", request);
-        let result = self.client.write().await.ping(request).await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("Ping", request, |request| async move {
+            client.ping(request).await
+        })
+        .await
     }
 
     async fn r#return(
@@ -159,7 +385,14 @@ impl LlbBridge for ProxyLlbServer {
     ) -> Result<Response<frontend::ReturnResponse>, tonic::Status> {
         // Do not send return request to buildkit
         let inner = request.into_inner();
-        self.result_sender.send(inner).unwrap();
+        self.result_sender.try_send(inner).map_err(|e| match e {
+            TrySendError::Full(_) => tonic::Status::failed_precondition(
+                "The Dockerfile frontend returned a result more than once",
+            ),
+            TrySendError::Disconnected(_) => {
+                tonic::Status::unavailable("dockerfile-plus is no longer waiting for a result")
+            }
+        })?;
         Ok(Response::new(frontend::ReturnResponse {}))
     }
 
@@ -167,9 +400,178 @@ impl LlbBridge for ProxyLlbServer {
         &self,
         request: Request<frontend::InputsRequest>,
     ) -> Result<Response<frontend::InputsResponse>, tonic::Status> {
-        eprintln!("Inputs: {:?}", request);
-        let result = self.client.write().await.inputs(request).await;
-        eprintln!("{:?}", result);
-        result
+        let mut client = self.client.clone();
+        logged("Inputs", request, |request| async move {
+            client.inputs(request).await
+        })
+        .await
     }
 }
+
+#[tokio::test]
+async fn proxy_rejects_unexpected_returns() {
+    use crate::mock_bridge::MockBridge;
+
+    let client = MockBridge::default().connect().await;
+    let (tx, rx) = channel::bounded(1);
+    let server = ProxyLlbServer::new(client, tx, "Dockerfile".to_string(), vec![0xff]);
+
+    // The expanded Dockerfile is served as is, even if it isn't valid UTF-8.
+    let response = server
+        .read_file(Request::new(frontend::ReadFileRequest {
+            file_path: "Dockerfile".to_string(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.into_inner().data, vec![0xff]);
+
+    let result = || Request::new(frontend::ReturnRequest::default());
+    server.r#return(result()).await.unwrap();
+    let status = server.r#return(result()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    rx.recv().unwrap();
+    drop(rx);
+    let status = server.r#return(result()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unavailable);
+}
+
+#[tokio::test]
+async fn forwards_requests_concurrently() {
+    use crate::mock_bridge::MockBridge;
+    use std::time::Duration;
+
+    let bridge = MockBridge {
+        solve_delay: Duration::from_millis(50),
+        ..Default::default()
+    };
+    let concurrent_solves = bridge.concurrent_solves.clone();
+    let client = bridge.connect().await;
+    let (tx, _rx) = channel::bounded(1);
+    let server = ProxyLlbServer::new(client, tx, "Dockerfile".to_string(), vec![]);
+
+    let results = futures::future::join_all(
+        (0..16).map(|_| server.solve(Request::new(frontend::SolveRequest::default()))),
+    )
+    .await;
+
+    assert!(results.iter().all(Result::is_ok));
+    // Forwarding one request at a time would never have two solves in progress.
+    let (_, most) = *concurrent_solves.lock().unwrap();
+    assert!(most > 1, "At most {} solve was in progress", most);
+}
+
+/// Forwards 16 solves taking 10ms each at a time. Run with
+/// `RUSTFLAGS="--cfg nightly" cargo +nightly bench`.
+#[cfg(nightly)]
+#[bench]
+fn bench_concurrent_requests(bencher: &mut test::Bencher) {
+    use crate::mock_bridge::MockBridge;
+    use std::time::Duration;
+
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let (server, _rx) = runtime.block_on(async {
+        let client = MockBridge {
+            solve_delay: Duration::from_millis(10),
+            ..Default::default()
+        }
+        .connect()
+        .await;
+        let (tx, rx) = channel::bounded(1);
+        let server = ProxyLlbServer::new(client, tx, "Dockerfile".to_string(), vec![]);
+        (server, rx)
+    });
+
+    bencher.iter(|| {
+        runtime.block_on(futures::future::join_all(
+            (0..16).map(|_| server.solve(Request::new(frontend::SolveRequest::default()))),
+        ))
+    });
+}
+
+#[tokio::test]
+async fn proxy_serves_the_expanded_dockerfile() {
+    use crate::mock_bridge::MockBridge;
+
+    let client = MockBridge::default()
+        .with_file("Dockerfile", "FROM alpine\nINCLUDE+ common.Dockerfile")
+        .with_file("common.Dockerfile", "RUN echo common")
+        .connect()
+        .await;
+    let (tx, _rx) = channel::bounded(1);
+    let server = ProxyLlbServer::new(
+        client,
+        tx,
+        "Dockerfile".to_string(),
+        b"FROM alpine\nRUN echo common".to_vec(),
+    );
+
+    let read = |path: &str| {
+        server.read_file(Request::new(frontend::ReadFileRequest {
+            file_path: path.to_string(),
+            ..Default::default()
+        }))
+    };
+    let dockerfile = read("Dockerfile").await.unwrap().into_inner().data;
+    assert_eq!(dockerfile, b"FROM alpine\nRUN echo common");
+    let other = read("common.Dockerfile").await.unwrap().into_inner().data;
+    assert_eq!(other, b"RUN echo common");
+
+    let entries = server
+        .read_dir(Request::new(frontend::ReadDirRequest {
+            include_pattern: "*.Dockerfile".to_string(),
+            ..Default::default()
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .entries;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].path, "common.Dockerfile");
+}
+
+#[tokio::test]
+async fn reports_frontends_that_exit_without_returning() {
+    use crate::mock_bridge::MockBridge;
+
+    let client = MockBridge::default().connect().await;
+    let options = options::from_env(vec![]).unwrap();
+    let frontend = DockerfileFrontend::new(client, "Dockerfile", options).with_command(
+        "sh",
+        &[
+            "-c",
+            "echo parsing >&2; echo 'unknown instruction: INCLUED+' >&2; exit 3",
+        ],
+    );
+    let error = frontend.solve("FROM alpine").await.unwrap_err();
+    let message = format!("{:#}", error);
+    assert!(message.contains("without returning a result (exit status: 3)"));
+    assert!(message.ends_with("parsing\nunknown instruction: INCLUED+"));
+    let status = crate::error::to_status(&error);
+    assert_eq!(status.code, tonic::Code::Internal as i32);
+}
+
+#[tokio::test]
+async fn kills_frontends_that_time_out() {
+    use crate::mock_bridge::MockBridge;
+
+    let client = MockBridge::default().connect().await;
+    let options: DockerfileOptions = options::from_env(vec![]).unwrap();
+    let frontend = DockerfileFrontend::new(client, "Dockerfile", options.clone())
+        .with_command("sleep", &["60"]);
+    // The timeout of the options of each solve applies, as with the solves of every target.
+    let options = DockerfileOptions {
+        frontend_timeout: Some(1),
+        ..options
+    };
+    let start = std::time::Instant::now();
+    let error = frontend
+        .solve_with_options("FROM alpine", &options)
+        .await
+        .unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    let status = crate::error::to_status(&error);
+    assert_eq!(status.code, tonic::Code::DeadlineExceeded as i32);
+    assert!(status.message.contains("did not finish within 1s"));
+}
@@ -0,0 +1,377 @@
+use std::fmt;
+
+use buildkit_frontend::Unsupported;
+use buildkit_proto::{google::rpc::Status, pb::Definition};
+use prost::Message;
+use tonic::Code;
+
+use crate::expand::{Origin, SourceFile};
+
+/// Type URL of BuildKit's `errdefs.Source`, attached to the details of a returned status for each
+/// file an error is located in.
+pub const SOURCE_TYPE_URL: &str = "type.googleapis.com/errdefs.Source";
+
+/// What went wrong, which decides the gRPC code returned to BuildKit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// A frontend option has an invalid value.
+    InvalidOption,
+    /// A `+` directive is malformed, or failed linting.
+    Parse,
+    /// An included file could not be fetched.
+    IncludeNotFound,
+    /// An included file includes itself, directly or not.
+    Cycle,
+    /// An include path points outside of the build context.
+    Blocked,
+    /// An included file doesn't match its pinned digest or the lockfile.
+    Mismatch,
+    /// One of the `dockerfile-plus.max-*` limits was exceeded.
+    LimitExceeded,
+    /// The Dockerfile frontend that builds the expanded Dockerfile failed.
+    Frontend,
+    /// The Dockerfile frontend didn't finish within `dockerfile-plus.frontend-timeout`.
+    Timeout,
+    /// BuildKit lacks a capability a directive or option needs.
+    Unsupported,
+    /// A request to BuildKit failed with this code.
+    Gateway(Code),
+    /// Anything else.
+    Unknown,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> Code {
+        match self {
+            ErrorKind::InvalidOption | ErrorKind::Parse => Code::InvalidArgument,
+            ErrorKind::IncludeNotFound => Code::NotFound,
+            ErrorKind::Cycle | ErrorKind::Mismatch => Code::FailedPrecondition,
+            ErrorKind::Blocked => Code::PermissionDenied,
+            ErrorKind::LimitExceeded => Code::ResourceExhausted,
+            ErrorKind::Frontend => Code::Internal,
+            ErrorKind::Timeout => Code::DeadlineExceeded,
+            ErrorKind::Unsupported => Code::Unimplemented,
+            ErrorKind::Gateway(code) => code,
+            ErrorKind::Unknown => Code::Unknown,
+        }
+    }
+}
+
+/// Where in the Dockerfile, or in an included file, an error happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    /// Line in `file`, if the error is about a single line.
+    pub line: Option<usize>,
+    /// The `INCLUDE+` directives leading to `file`, innermost first.
+    pub include_chain: Vec<Origin>,
+    /// Contents of `file` and of the files in `include_chain`, as far as they are known.
+    pub sources: Vec<SourceFile>,
+}
+
+/// An error of dockerfile-plus itself. Other errors are reported with `Code::Unknown`.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub location: Option<Location>,
+}
+
+impl Error {
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Error {
+        Error {
+            kind,
+            message: message.into(),
+            location: None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}", location.file)?;
+            if let Some(line) = location.line {
+                write!(f, ":{}", line)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            for origin in &location.include_chain {
+                write!(f, "\n  included from {}:{}", origin.file, origin.line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+// BuildKit's `errdefs.Source` and the `pb` messages it is made of, which were added after the
+// BuildKit version `buildkit-proto` is generated from.
+
+/// A file an error is located in, as BuildKit clients show it.
+#[derive(Clone, PartialEq, Message)]
+pub struct Source {
+    #[prost(message, optional, tag = "1")]
+    pub info: Option<SourceInfo>,
+    /// Empty when the error isn't about a single line.
+    #[prost(message, repeated, tag = "2")]
+    pub ranges: Vec<Range>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SourceInfo {
+    #[prost(string, tag = "1")]
+    pub filename: String,
+    #[prost(bytes, tag = "2")]
+    pub data: Vec<u8>,
+    #[prost(message, optional, tag = "3")]
+    pub definition: Option<Definition>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Range {
+    #[prost(message, optional, tag = "1")]
+    pub start: Option<Position>,
+    #[prost(message, optional, tag = "2")]
+    pub end: Option<Position>,
+}
+
+/// A 1-based line and 0-based character.
+#[derive(Clone, PartialEq, Message)]
+pub struct Position {
+    #[prost(int32, tag = "1")]
+    pub line: i32,
+    #[prost(int32, tag = "2")]
+    pub character: i32,
+}
+
+fn find(error: &anyhow::Error) -> Option<&Error> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<Error>())
+}
+
+/// Kind of an error, if it is one of ours or a capability BuildKit lacks.
+fn kind(error: &anyhow::Error) -> Option<ErrorKind> {
+    error.chain().find_map(|cause| {
+        if let Some(typed) = cause.downcast_ref::<Error>() {
+            Some(typed.kind)
+        } else if cause.is::<Unsupported>() {
+            Some(ErrorKind::Unsupported)
+        } else {
+            None
+        }
+    })
+}
+
+/// Code of the gRPC status an error was caused by, if any.
+fn status_code(error: &anyhow::Error) -> Option<Code> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<tonic::Status>())
+        .map(tonic::Status::code)
+}
+
+/// Whether an error was caused by a file, or anything else, that doesn't exist.
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    status_code(error) == Some(Code::NotFound)
+}
+
+/// Gives `kind` to an error that doesn't have one yet.
+pub fn classify(error: anyhow::Error, kind: ErrorKind) -> anyhow::Error {
+    if self::kind(&error).is_some() {
+        return error;
+    }
+    Error::new(kind, format!("{:#}", error)).into()
+}
+
+/// Records where an error happened, unless a more precise location is already known. `sources`
+/// are the files read so far, which the location keeps the contents of.
+pub fn locate(
+    error: anyhow::Error,
+    file: &str,
+    line: Option<usize>,
+    include_chain: &[Origin],
+    sources: &[SourceFile],
+) -> anyhow::Error {
+    let location = Location {
+        file: file.to_string(),
+        line,
+        include_chain: include_chain.iter().rev().cloned().collect(),
+        sources: sources
+            .iter()
+            .filter(|source| {
+                source.file == file
+                    || include_chain
+                        .iter()
+                        .any(|origin| origin.file == source.file)
+            })
+            .cloned()
+            .collect(),
+    };
+    match error.downcast::<Error>() {
+        Ok(mut typed) => {
+            if typed.location.is_none() {
+                typed.location = Some(location);
+            }
+            typed.into()
+        }
+        Err(error) => {
+            let kind = match (self::kind(&error), status_code(&error)) {
+                (Some(kind), _) => kind,
+                (None, Some(Code::NotFound)) => ErrorKind::IncludeNotFound,
+                (None, Some(code)) => ErrorKind::Gateway(code),
+                (None, None) => ErrorKind::Unknown,
+            };
+            Error {
+                kind,
+                message: format!("{:#}", error),
+                location: Some(location),
+            }
+            .into()
+        }
+    }
+}
+
+/// Turns an error into the status returned to BuildKit, with the full chain of causes in the
+/// message and the location of the error, if known, in the details: a `Source` for the file the
+/// error is in, followed by one for each `INCLUDE+` directive leading to it.
+pub fn to_status(error: &anyhow::Error) -> Status {
+    let typed = find(error);
+    let code = match kind(error) {
+        Some(kind) => kind.code(),
+        None => status_code(error).unwrap_or(Code::Unknown),
+    };
+
+    let details = typed
+        .and_then(|typed| typed.location.as_ref())
+        .map(|location| {
+            let innermost = (location.file.as_str(), location.line);
+            let includes = location
+                .include_chain
+                .iter()
+                .map(|origin| (origin.file.as_str(), Some(origin.line)));
+            std::iter::once(innermost)
+                .chain(includes)
+                .map(|(file, line)| source_detail(location, file, line))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Status {
+        code: code as i32,
+        message: format!("{:#}", error),
+        details,
+    }
+}
+
+fn source_detail(location: &Location, file: &str, line: Option<usize>) -> prost_types::Any {
+    let data = location
+        .sources
+        .iter()
+        .find(|source| source.file == file)
+        .map(|source| source.contents.as_bytes().to_vec())
+        .unwrap_or_default();
+    let position = |line| Position {
+        line: line as i32,
+        character: 0,
+    };
+    let detail = Source {
+        info: Some(SourceInfo {
+            filename: file.to_string(),
+            data,
+            definition: None,
+        }),
+        ranges: line
+            .map(|line| Range {
+                start: Some(position(line)),
+                end: Some(position(line)),
+            })
+            .into_iter()
+            .collect(),
+    };
+    let mut value = vec![];
+    detail
+        .encode(&mut value)
+        .expect("encoding into a Vec can't fail");
+    prost_types::Any {
+        type_url: SOURCE_TYPE_URL.to_string(),
+        value,
+    }
+}
+
+#[test]
+fn located_errors_carry_code_and_details() {
+    use anyhow::Context;
+
+    let origin = |file: &str, line| Origin {
+        file: file.to_string(),
+        line,
+        include_chain: vec![],
+    };
+    let source = |file: &str, contents: &str| SourceFile {
+        file: file.to_string(),
+        contents: contents.to_string(),
+    };
+    let chain = vec![origin("Dockerfile", 5), origin("common.Dockerfile", 2)];
+    let sources = vec![
+        source("Dockerfile", "FROM alpine"),
+        source("common.Dockerfile", "RUN echo common"),
+        source("base.Dockerfile", "FROM base"),
+        source("unrelated.Dockerfile", "RUN echo unrelated"),
+    ];
+
+    let error = locate(
+        Error::new(ErrorKind::Cycle, "INCLUDE+ cycle").into(),
+        "base.Dockerfile",
+        Some(3),
+        &chain,
+        &sources,
+    );
+    // Outer levels of the expansion don't override the innermost location.
+    let error = locate(error, "Dockerfile", Some(5), &[], &sources);
+    let status = to_status(&error);
+
+    assert_eq!(status.code, Code::FailedPrecondition as i32);
+    assert_eq!(
+        status.message,
+        "base.Dockerfile:3: INCLUDE+ cycle\n  included from common.Dockerfile:2\n  included from Dockerfile:5"
+    );
+    assert!(status
+        .details
+        .iter()
+        .all(|detail| detail.type_url == SOURCE_TYPE_URL));
+    let details = status
+        .details
+        .iter()
+        .map(|detail| {
+            let detail = Source::decode(&detail.value[..]).unwrap();
+            let info = detail.info.unwrap();
+            let line = detail.ranges[0].start.as_ref().unwrap().line;
+            (info.filename, String::from_utf8(info.data).unwrap(), line)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        details,
+        vec![
+            ("base.Dockerfile".to_string(), "FROM base".to_string(), 3),
+            (
+                "common.Dockerfile".to_string(),
+                "RUN echo common".to_string(),
+                2
+            ),
+            ("Dockerfile".to_string(), "FROM alpine".to_string(), 5),
+        ]
+    );
+
+    let untyped = Err::<(), _>(tonic::Status::not_found("no such file"))
+        .context("Could not read file")
+        .unwrap_err();
+    let status = to_status(&untyped);
+    assert_eq!(status.code, Code::NotFound as i32);
+    assert!(status.message.starts_with("Could not read file: "));
+    assert!(status.details.is_empty());
+}
@@ -0,0 +1,439 @@
+use anyhow::{bail, Result};
+use buildkit_frontend::{Bridge, Reference};
+use buildkit_proto::moby::buildkit::v1::frontend::FileRange;
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::error::{classify, is_not_found, locate, Error, ErrorKind};
+use crate::include::{sha256_hex, Include};
+use crate::lint::{lint_file, Diagnostic, LintLevel};
+use crate::lock::Lock;
+use crate::matrix::expand_matrices;
+use crate::platform::Platform;
+
+const INCLUDE_COMMAND: &str = "INCLUDE+";
+
+/// Limits on how much text a Dockerfile may expand to.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum size in bytes of a single included file.
+    pub max_include_size: u64,
+    /// Maximum size in bytes of the fully expanded Dockerfile.
+    pub max_expanded_size: u64,
+    /// Maximum number of `INCLUDE+` directives, counting nested ones.
+    pub max_includes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_include_size: 1024 * 1024,
+            max_expanded_size: 16 * 1024 * 1024,
+            max_includes: 1000,
+        }
+    }
+}
+
+/// Where a line of the expanded Dockerfile comes from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+    /// The `INCLUDE+` directives leading to `file`, innermost first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include_chain: Vec<Origin>,
+}
+
+/// An `INCLUDE+` directive that was expanded, where it was found and what it resolved to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IncludeRecord {
+    pub file: String,
+    pub line: usize,
+    pub include: String,
+    /// One of `context`, `http`, `git` or `image`.
+    pub source: &'static str,
+    /// Git reference or pinned image the file was read from, if any.
+    pub reference: Option<String>,
+    /// Digest of the included contents.
+    pub digest: String,
+}
+
+/// Maps the expanded Dockerfile back to the files it was assembled from.
+#[derive(Debug, Default, Serialize)]
+pub struct IncludeMap {
+    /// Every expanded `INCLUDE+` directive, in expansion order.
+    pub includes: Vec<IncludeRecord>,
+    /// Origin of each line of the expanded Dockerfile.
+    pub lines: Vec<Origin>,
+}
+
+/// Result of expanding a Dockerfile.
+#[derive(Debug)]
+pub struct Expanded {
+    pub dockerfile: String,
+    pub include_map: IncludeMap,
+    /// The Dockerfile and every included file, once each, in expansion order.
+    pub sources: Vec<SourceFile>,
+}
+
+/// A file the expanded Dockerfile was assembled from, as it was read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub file: String,
+    pub contents: String,
+}
+
+/// Expands the `+` directives of a Dockerfile for one target platform, one line at a time.
+pub struct Expansion<'a> {
+    bridge: &'a mut Bridge,
+    context_layer: &'a Reference,
+    lock: &'a mut Lock,
+    limits: Limits,
+    lint: Option<LintLevel>,
+    platform: &'a Platform,
+
+    size: u64,
+    lines: Vec<String>,
+    include_map: IncludeMap,
+    sources: Vec<SourceFile>,
+    /// Keys of the files being expanded and the `INCLUDE+` directives that led to them,
+    /// outermost first.
+    files: Vec<String>,
+    include_chain: Vec<Origin>,
+}
+
+impl<'a> Expansion<'a> {
+    pub fn new(
+        bridge: &'a mut Bridge,
+        context_layer: &'a Reference,
+        lock: &'a mut Lock,
+        limits: Limits,
+        lint: Option<LintLevel>,
+        platform: &'a Platform,
+    ) -> Self {
+        Expansion {
+            bridge,
+            context_layer,
+            lock,
+            limits,
+            lint,
+            platform,
+
+            size: 0,
+            lines: vec![],
+            include_map: IncludeMap::default(),
+            sources: vec![],
+            files: vec![],
+            include_chain: vec![],
+        }
+    }
+
+    /// Expands the contents of the Dockerfile, `file`.
+    pub fn expand<'b>(&'b mut self, file: &'b str, contents: &'b str) -> BoxFuture<'b, Result<()>> {
+        self.expand_file(file, file, contents)
+    }
+
+    /// Expands the contents of `file`, which is either the Dockerfile or an included file, known
+    /// as `key` to detect cycles.
+    ///
+    /// Included files are expanded recursively, hence the boxed future.
+    fn expand_file<'b>(
+        &'b mut self,
+        file: &'b str,
+        key: &'b str,
+        contents: &'b str,
+    ) -> BoxFuture<'b, Result<()>> {
+        async move {
+            if !self.sources.iter().any(|source| source.file == file) {
+                self.sources.push(SourceFile {
+                    file: file.to_string(),
+                    contents: contents.to_string(),
+                });
+            }
+            self.files.push(key.to_string());
+            let result = self.expand_lines(file, contents).await;
+            self.files.pop();
+            result
+        }
+        .boxed()
+    }
+
+    async fn expand_lines(&mut self, file: &str, contents: &str) -> Result<()> {
+        if self.lint.is_some() {
+            self.report(lint_file(file, contents))
+                .map_err(|e| locate(e, file, None, &self.include_chain, &self.sources))?;
+        }
+        let lines = expand_matrices(contents).map_err(|e| {
+            locate(
+                classify(e, ErrorKind::Parse),
+                file,
+                None,
+                &self.include_chain,
+                &self.sources,
+            )
+        })?;
+        for (line_number, line) in lines {
+            self.replace(file, line_number, &line).await.map_err(|e| {
+                locate(
+                    e,
+                    file,
+                    Some(line_number),
+                    &self.include_chain,
+                    &self.sources,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Expanded {
+        Expanded {
+            dockerfile: self.lines.join("\n"),
+            include_map: self.include_map,
+            sources: self.sources,
+        }
+    }
+
+    async fn replace(&mut self, file: &str, line_number: usize, line: &str) -> Result<()> {
+        if let Some(args) = line.trim().strip_prefix(INCLUDE_COMMAND) {
+            let include = Include::parse(&self.platform.substitute(args))
+                .map_err(|e| classify(e, ErrorKind::Parse))?;
+
+            if let Some(platform) = &include.platform {
+                let matches = self
+                    .platform
+                    .matches(platform)
+                    .map_err(|e| classify(e, ErrorKind::Parse))?;
+                if !matches {
+                    debug!(
+                        "{}:{}: skipping {}, which is not for {}",
+                        file,
+                        line_number,
+                        include.key(),
+                        self.platform
+                    );
+                    self.lock.skip(&include);
+                    return Ok(());
+                }
+            }
+
+            if self.include_map.includes.len() >= self.limits.max_includes {
+                bail!(Error::new(
+                    ErrorKind::LimitExceeded,
+                    format!(
+                        "Too many INCLUDE+ directives: the limit is {} (set dockerfile-plus.max-includes to raise it)",
+                        self.limits.max_includes
+                    )
+                ));
+            }
+
+            // Ask for one byte more than allowed, so oversized files can be told apart
+            // without transferring them in full.
+            let range = FileRange {
+                offset: 0,
+                length: self.limits.max_include_size as i64 + 1,
+            };
+            let pinned = self.lock.pinned(&include);
+            let fetched = include
+                .fetch(
+                    self.bridge,
+                    self.context_layer,
+                    Some(range),
+                    pinned.as_deref(),
+                )
+                .await
+                .map_err(|e| {
+                    // Only a missing file is a missing include: other failures of the gateway,
+                    // like a cancelled build, keep their own code.
+                    if is_not_found(&e) {
+                        classify(e, ErrorKind::IncludeNotFound)
+                    } else {
+                        e
+                    }
+                })?;
+            // Context includes are told apart by the path they resolve to, however they are
+            // written.
+            if self.files.contains(&fetched.key) {
+                bail!(Error::new(
+                    ErrorKind::Cycle,
+                    format!(
+                        "INCLUDE+ cycle: {} -> {}",
+                        self.files.join(" -> "),
+                        fetched.key
+                    )
+                ));
+            }
+            let bytes = fetched.bytes;
+            if bytes.len() as u64 > self.limits.max_include_size {
+                bail!(Error::new(
+                    ErrorKind::LimitExceeded,
+                    format!(
+                        "INCLUDE+ file \"{}\" is larger than {} bytes (set dockerfile-plus.max-include-size to raise the limit)",
+                        include.path,
+                        self.limits.max_include_size
+                    )
+                ));
+            }
+
+            include.verify(&bytes)?;
+            self.lock
+                .check(&include, &bytes, fetched.reference.as_deref())?;
+            let contents = std::str::from_utf8(&bytes).map_err(|e| {
+                Error::new(
+                    ErrorKind::Parse,
+                    format!(
+                        "INCLUDE+ file \"{}\" is not valid UTF-8: {}",
+                        include.key(),
+                        e
+                    ),
+                )
+            })?;
+
+            if self.lint.is_some() && !has_instructions(contents) {
+                self.report(vec![Diagnostic {
+                    file: file.to_string(),
+                    line: line_number,
+                    message: format!(
+                        "\"{}\" is unused: the included file has no instructions",
+                        include.key()
+                    ),
+                }])?;
+            }
+
+            info!(
+                "{}:{}: including {} ({} bytes)",
+                file,
+                line_number,
+                include.key(),
+                bytes.len()
+            );
+            self.include_map.includes.push(IncludeRecord {
+                file: file.to_string(),
+                line: line_number,
+                include: include.key(),
+                source: include.source_kind(),
+                reference: fetched.reference,
+                digest: format!("sha256:{}", sha256_hex(&bytes)),
+            });
+            //recurse
+            self.include_chain.push(Origin {
+                file: file.to_string(),
+                line: line_number,
+                include_chain: vec![],
+            });
+            let name = include.key();
+            let result = self.expand_file(&name, &fetched.key, contents).await;
+            self.include_chain.pop();
+            result?;
+        } else {
+            self.size += line.len() as u64 + 1;
+            if self.size > self.limits.max_expanded_size {
+                bail!(Error::new(
+                    ErrorKind::LimitExceeded,
+                    format!(
+                        "The expanded Dockerfile is larger than {} bytes (set dockerfile-plus.max-expanded-size to raise the limit)",
+                        self.limits.max_expanded_size
+                    )
+                ));
+            }
+            self.lines.push(line.to_string());
+            self.include_map.lines.push(Origin {
+                file: file.to_string(),
+                line: line_number,
+                include_chain: self.include_chain.iter().rev().cloned().collect(),
+            });
+        }
+        Ok(())
+    }
+
+    fn report(&self, diagnostics: Vec<Diagnostic>) -> Result<()> {
+        match self.lint {
+            Some(LintLevel::Error) if !diagnostics.is_empty() => bail!(Error::new(
+                ErrorKind::Parse,
+                format!(
+                    "dockerfile-plus lint failed:\n{}",
+                    diagnostics
+                        .iter()
+                        .map(Diagnostic::to_string)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                )
+            )),
+            _ => {
+                for diagnostic in diagnostics {
+                    warn!("{}", diagnostic);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn has_instructions(contents: &str) -> bool {
+    contents
+        .lines()
+        .map(str::trim)
+        .any(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+#[cfg(test)]
+async fn expand_with(limits: Limits) -> Result<Expanded> {
+    use crate::lock::{LockMode, Lockfile};
+    use crate::mock_bridge::MockBridge;
+
+    let mut bridge = Bridge::new(
+        MockBridge::default()
+            .with_file("common.Dockerfile", "RUN echo common\nRUN echo again\n")
+            .connect()
+            .await,
+    );
+    let context = Reference::new("context");
+    let mut lock = Lock::new(LockMode::Off, Lockfile::default());
+    let platform = Platform::default();
+    let mut expansion = Expansion::new(&mut bridge, &context, &mut lock, limits, None, &platform);
+    expansion
+        .expand(
+            "Dockerfile",
+            "FROM alpine\nINCLUDE+ common.Dockerfile\nINCLUDE+ common.Dockerfile\n",
+        )
+        .await?;
+    Ok(expansion.finish())
+}
+
+#[tokio::test]
+async fn enforces_limits() {
+    let expanded = expand_with(Limits::default()).await.unwrap();
+    assert_eq!(expanded.include_map.includes.len(), 2);
+
+    let exceeded = |limits, option: &'static str| async move {
+        let error = expand_with(limits).await.unwrap_err();
+        let status = crate::error::to_status(&error);
+        assert_eq!(status.code, tonic::Code::ResourceExhausted as i32);
+        assert!(status.message.contains(option), "{}", status.message);
+    };
+    exceeded(
+        Limits {
+            max_include_size: 10,
+            ..Limits::default()
+        },
+        "set dockerfile-plus.max-include-size to raise the limit",
+    )
+    .await;
+    exceeded(
+        Limits {
+            max_expanded_size: 40,
+            ..Limits::default()
+        },
+        "set dockerfile-plus.max-expanded-size to raise the limit",
+    )
+    .await;
+    exceeded(
+        Limits {
+            max_includes: 1,
+            ..Limits::default()
+        },
+        "set dockerfile-plus.max-includes to raise it",
+    )
+    .await;
+}
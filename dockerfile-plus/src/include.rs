@@ -0,0 +1,355 @@
+use anyhow::{bail, Context, Result};
+use buildkit_frontend::caps::{
+    CAP_READ_FILE, CAP_RESOLVE_IMAGE, CAP_SOURCE_GIT, CAP_SOURCE_HTTP, CAP_SOURCE_IMAGE,
+    CAP_STAT_FILE,
+};
+use buildkit_frontend::{Bridge, Capabilities, Reference};
+use buildkit_llb::ops::source::ImageSource;
+use buildkit_llb::prelude::*;
+use buildkit_proto::moby::buildkit::v1::frontend::FileRange;
+use sha2::{Digest, Sha256};
+
+use crate::confine::resolve_in_context;
+use crate::error::{Error, ErrorKind};
+
+/// File name used to store the body of an included http resource.
+const HTTP_INCLUDE_FILE_NAME: &str = "include";
+
+/// Arguments of an `INCLUDE+` directive:
+/// `INCLUDE+ [--sha256=<hex>] [--from=<git url|image>] [--platform=<platform>] <path|http url>`.
+#[derive(Debug, PartialEq)]
+pub struct Include {
+    pub source: IncludeSource,
+    pub path: String,
+    pub sha256: Option<String>,
+    /// Only include the file when building for this platform.
+    pub platform: Option<String>,
+}
+
+/// Where the contents of an `INCLUDE+` directive come from.
+#[derive(Debug, PartialEq)]
+pub enum IncludeSource {
+    /// A file in the build context.
+    Context,
+    /// A file served over http(s). The include path is the URL.
+    Http,
+    /// A file in a git repository, optionally at a given reference.
+    Git {
+        url: String,
+        reference: Option<String>,
+    },
+    /// A file in a container image.
+    Image(String),
+}
+
+/// Contents of an included file, and the reference they were resolved from.
+#[derive(Debug)]
+pub struct Fetched {
+    pub bytes: Vec<u8>,
+    /// Identifies the file that was read, to detect cycles: the normalized path in the build
+    /// context of context includes, or `Include::key` for remote ones.
+    pub key: String,
+    /// Git commit or pinned image the file was read from, if any.
+    pub reference: Option<String>,
+}
+
+impl Include {
+    pub fn parse(args: &str) -> Result<Include> {
+        let mut sha256 = None;
+        let mut from = None;
+        let mut platform = None;
+        let mut rest = args.trim_start();
+
+        while let Some(flag) = rest.strip_prefix("--") {
+            let end = flag.find(char::is_whitespace).unwrap_or(flag.len());
+            let (flag, remaining) = flag.split_at(end);
+            rest = remaining.trim_start();
+
+            let mut parts = flag.splitn(2, '=');
+            match (parts.next().unwrap(), parts.next()) {
+                ("sha256", Some(digest)) => sha256 = Some(parse_sha256(digest)?),
+                ("from", Some(from_value)) if !from_value.is_empty() => from = Some(from_value),
+                ("platform", Some(platform_value)) if !platform_value.is_empty() => {
+                    platform = Some(platform_value.to_string())
+                }
+                (name @ "sha256", _) | (name @ "from", _) | (name @ "platform", _) => {
+                    bail!(Error::new(
+                        ErrorKind::Parse,
+                        format!("INCLUDE+ flag --{} requires a value", name)
+                    ))
+                }
+                (name, _) => bail!(Error::new(
+                    ErrorKind::Parse,
+                    format!("Unknown INCLUDE+ flag \"--{}\"", name)
+                )),
+            }
+        }
+
+        let path = rest.trim_end();
+        if path.is_empty() {
+            bail!(Error::new(
+                ErrorKind::Parse,
+                "INCLUDE+ requires a file path"
+            ));
+        }
+
+        let source = match from {
+            Some(from) if from.contains("://") || from.starts_with("git@") => {
+                let mut parts = from.splitn(2, '#');
+                IncludeSource::Git {
+                    url: parts.next().unwrap().to_string(),
+                    reference: parts.next().map(str::to_string),
+                }
+            }
+            Some(image) => IncludeSource::Image(image.to_string()),
+            None if path.starts_with("http://") || path.starts_with("https://") => {
+                IncludeSource::Http
+            }
+            None => IncludeSource::Context,
+        };
+
+        Ok(Include {
+            source,
+            path: path.to_string(),
+            sha256,
+            platform,
+        })
+    }
+
+    /// Human readable description of the included file, unique per source and path.
+    pub fn key(&self) -> String {
+        self.lock_key().unwrap_or_else(|| self.path.clone())
+    }
+
+    /// Key identifying a remote include in the lockfile. Context includes are pinned
+    /// by the build context itself and don't have one.
+    pub fn lock_key(&self) -> Option<String> {
+        match &self.source {
+            IncludeSource::Context => None,
+            IncludeSource::Http => Some(self.path.clone()),
+            IncludeSource::Git {
+                url,
+                reference: Some(reference),
+            } => Some(format!("{}#{} {}", url, reference, self.path)),
+            IncludeSource::Git { url, .. } => Some(format!("{} {}", url, self.path)),
+            IncludeSource::Image(image) => Some(format!("{} {}", image, self.path)),
+        }
+    }
+
+    /// Name of the kind of source, as reported in the provenance metadata.
+    pub fn source_kind(&self) -> &'static str {
+        match self.source {
+            IncludeSource::Context => "context",
+            IncludeSource::Http => "http",
+            IncludeSource::Git { .. } => "git",
+            IncludeSource::Image(_) => "image",
+        }
+    }
+
+    /// Reads the contents of the included file. Git and image includes are read at `pinned`,
+    /// a reference they resolved to before, if there is one.
+    pub async fn fetch(
+        &self,
+        bridge: &mut Bridge,
+        context_layer: &Reference,
+        range: Option<FileRange>,
+        pinned: Option<&str>,
+    ) -> Result<Fetched> {
+        self.check_capabilities(bridge.capabilities())?;
+        let mut key = self.key();
+        let (bytes, reference) = match &self.source {
+            IncludeSource::Context => {
+                let path = resolve_in_context(bridge, context_layer, &self.path).await?;
+                key = path.clone();
+                let bytes = bridge.read_file(context_layer, path, range)
+                    .await
+                    .with_context(|| format!("Could not read file \"{}\". Remember that the file path is relative to the build context, not the Dockerfile path.", self.path))?;
+                (bytes, None)
+            }
+
+            IncludeSource::Http => {
+                let source = Source::http(&self.path).with_file_name(HTTP_INCLUDE_FILE_NAME);
+                let layer = bridge.solve(Terminal::with(source.output())).await?;
+                let bytes = bridge
+                    .read_file(&layer, HTTP_INCLUDE_FILE_NAME, range)
+                    .await
+                    .with_context(|| format!("Could not download \"{}\"", self.path))?;
+                (bytes, None)
+            }
+
+            IncludeSource::Git { url, reference } => {
+                let source = match pinned.or(reference.as_deref()) {
+                    Some(reference) => Source::git(url).with_reference(reference),
+                    None => Source::git(url),
+                };
+                let source = source.with_keep_git_dir(true);
+                let layer = bridge.solve(Terminal::with(source.output())).await?;
+                let bytes = bridge
+                    .read_file(&layer, &self.path, range)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Could not read file \"{}\" from git repository \"{}\"",
+                            self.path, url
+                        )
+                    })?;
+                let commit = resolve_commit(bridge, &layer)
+                    .await
+                    .with_context(|| format!("Could not resolve the commit of \"{}\"", url))?;
+                (bytes, Some(commit))
+            }
+
+            IncludeSource::Image(image) => {
+                let source = resolve_image(bridge, pinned.unwrap_or(image)).await?;
+                let layer = bridge.solve(Terminal::with(source.output())).await?;
+                let bytes = bridge
+                    .read_file(&layer, &self.path, range)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Could not read file \"{}\" from image \"{}\"",
+                            self.path, image
+                        )
+                    })?;
+                (bytes, Some(source.canonical_name()))
+            }
+        };
+
+        Ok(Fetched {
+            bytes,
+            key,
+            reference,
+        })
+    }
+
+    /// Fails if BuildKit is too old to fetch this include.
+    fn check_capabilities(&self, capabilities: &Capabilities) -> Result<()> {
+        let (feature, caps): (&str, &[&str]) = match &self.source {
+            IncludeSource::Context => ("INCLUDE+", &[CAP_STAT_FILE, CAP_READ_FILE]),
+            IncludeSource::Http => ("INCLUDE+ <http url>", &[CAP_SOURCE_HTTP]),
+            IncludeSource::Git { .. } => ("INCLUDE+ --from=<git repository>", &[CAP_SOURCE_GIT]),
+            IncludeSource::Image(_) => (
+                "INCLUDE+ --from=<image>",
+                &[CAP_SOURCE_IMAGE, CAP_RESOLVE_IMAGE],
+            ),
+        };
+        for cap in caps {
+            capabilities.require(cap, feature)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the included bytes against the pinned digest, if there is one.
+    pub fn verify(&self, bytes: &[u8]) -> Result<()> {
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_hex(bytes);
+            if &actual != expected {
+                bail!(Error::new(
+                    ErrorKind::Mismatch,
+                    format!(
+                        "Digest mismatch for \"{}\": expected sha256:{}, got sha256:{}",
+                        self.path, expected, actual
+                    )
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the commit a git checkout, made with its `.git` directory, is at.
+async fn resolve_commit(bridge: &mut Bridge, layer: &Reference) -> Result<String> {
+    let mut path = ".git/HEAD".to_string();
+    // BuildKit checks out a detached HEAD, but follow a branch anyway.
+    for _ in 0..2 {
+        let head = String::from_utf8(bridge.read_file(layer, &path, None).await?)?;
+        let head = head.trim();
+        match head.strip_prefix("ref: ") {
+            Some(branch) => path = format!(".git/{}", branch),
+            None if head.len() == 40 && head.chars().all(|c| c.is_ascii_hexdigit()) => {
+                return Ok(head.to_ascii_lowercase())
+            }
+            None => bail!("\"{}\" is not a commit", head),
+        }
+    }
+    bail!("HEAD is a symbolic ref to a symbolic ref")
+}
+
+/// Pins an image reference to the digest it currently resolves to.
+async fn resolve_image(bridge: &mut Bridge, image: &str) -> Result<ImageSource> {
+    let source = Source::image(image);
+    if image.contains('@') {
+        return Ok(source);
+    }
+
+    let digest = bridge
+        .resolve_image_config(&source.canonical_name())
+        .await
+        .with_context(|| format!("Could not resolve image \"{}\"", image))?
+        .digest;
+
+    Ok(source.with_digest(digest))
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+
+    format!("{:x}", hasher.result())
+}
+
+fn parse_sha256(value: &str) -> Result<String> {
+    let hex = value.strip_prefix("sha256:").unwrap_or(value);
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!(Error::new(
+            ErrorKind::Parse,
+            format!(
+                "Invalid --sha256 value \"{}\": expected 64 hexadecimal characters",
+                value
+            )
+        ));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+#[test]
+fn parses_pinned_digests() {
+    let digest = sha256_hex(b"RUN echo common\n");
+    let include = Include::parse(&format!("--sha256=sha256:{} common.Dockerfile", digest)).unwrap();
+    assert_eq!(include.sha256.as_deref(), Some(digest.as_str()));
+    let include = Include::parse(&format!(
+        "--sha256={} common.Dockerfile",
+        digest.to_ascii_uppercase()
+    ))
+    .unwrap();
+    assert_eq!(include.sha256.as_deref(), Some(digest.as_str()));
+
+    for value in &[
+        &digest[1..],
+        &format!("{}0", digest),
+        &format!("{}g", &digest[1..]),
+    ] {
+        let error = Include::parse(&format!("--sha256={} common.Dockerfile", value)).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("expected 64 hexadecimal characters"));
+    }
+    assert!(Include::parse("--sha256= common.Dockerfile").is_err());
+}
+
+#[test]
+fn reports_digest_mismatches() {
+    let expected = sha256_hex(b"RUN echo common\n");
+    let include = Include::parse(&format!("--sha256={} common.Dockerfile", expected)).unwrap();
+    assert!(include.verify(b"RUN echo common\n").is_ok());
+
+    let actual = sha256_hex(b"RUN echo tampered\n");
+    let error = include.verify(b"RUN echo tampered\n").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Digest mismatch for \"common.Dockerfile\": expected sha256:{}, got sha256:{}",
+            expected, actual
+        )
+    );
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::include::Include;
+use crate::matrix::{Matrix, END_MATRIX_COMMAND, MATRIX_COMMAND};
+
+/// Every directive added by dockerfile-plus.
+pub const KNOWN_DIRECTIVES: &[&str] = &["INCLUDE+", MATRIX_COMMAND, END_MATRIX_COMMAND];
+
+/// Value of the `dockerfile-plus.lint` frontend option.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LintLevel {
+    /// Fail the build on the first file with lint findings.
+    Error,
+    /// Report lint findings on stderr and carry on with the build.
+    Warn,
+}
+
+/// A lint finding, located in the file it was found in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Checks the `+` directives of a single file, without resolving any includes.
+pub fn lint_file(file: &str, contents: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut includes: HashMap<String, usize> = HashMap::new();
+
+    let mut continued = false;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+
+        // Continuation lines are arguments of the previous instruction, not directives.
+        let is_continuation = continued;
+        continued = line.trim_end().ends_with('\\');
+        if is_continuation {
+            continue;
+        }
+
+        let mut diagnostic = |message: String| {
+            diagnostics.push(Diagnostic {
+                file: file.to_string(),
+                line: line_number,
+                message,
+            })
+        };
+
+        let trimmed = line.trim();
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        let mut words = trimmed.split_whitespace();
+        let (first, second) = match (words.next(), words.next()) {
+            (Some(first), second) => (first, second),
+            (None, _) => continue,
+        };
+
+        // `INCLUDE + foo` is a typo for `INCLUDE+ foo`.
+        if second == Some("+") {
+            let directive = format!("{}+", first);
+            if let Some(suggestion) = closest_directive(&directive) {
+                diagnostic(format!(
+                    "\"{} +\" is not a directive, did you mean \"{}\"?",
+                    first, suggestion
+                ));
+            }
+            continue;
+        }
+
+        // Directives may be followed by their arguments without whitespace, as in `INCLUDE+foo`.
+        let directive = match first.find('+') {
+            Some(end) => &first[..=end],
+            None => continue,
+        };
+
+        if directive == "INCLUDE+" {
+            match Include::parse(&trimmed[directive.len()..]) {
+                Ok(include) => {
+                    let key = match &include.platform {
+                        Some(platform) => format!("{} --platform={}", include.key(), platform),
+                        None => include.key(),
+                    };
+                    match includes.get(&key) {
+                        Some(first_line) => diagnostic(format!(
+                            "\"{}\" is included more than once (first at line {})",
+                            key, first_line
+                        )),
+                        None => {
+                            includes.insert(key, line_number);
+                        }
+                    }
+                }
+                Err(e) => diagnostic(e.to_string()),
+            }
+        } else if directive == MATRIX_COMMAND {
+            if let Err(e) = Matrix::parse(&trimmed[directive.len()..]) {
+                diagnostic(e.to_string());
+            }
+        } else if directive.len() == first.len() && !KNOWN_DIRECTIVES.contains(&directive) {
+            match closest_directive(directive) {
+                Some(suggestion) => diagnostic(format!(
+                    "Unknown directive \"{}\", did you mean \"{}\"?",
+                    directive, suggestion
+                )),
+                None => diagnostic(format!("Unknown directive \"{}\"", directive)),
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Finds the known directive closest to `directive`, if any is close enough to be a typo.
+fn closest_directive(directive: &str) -> Option<&'static str> {
+    let directive = directive.to_ascii_uppercase();
+    KNOWN_DIRECTIVES
+        .iter()
+        .map(|known| (edit_distance(&directive, known), *known))
+        .filter(|(distance, _)| *distance <= 3)
+        .min()
+        .map(|(_, known)| known)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Entry point of `dockerfile-plus lint <file>...`. Returns the process exit code.
+pub fn run_cli(files: &[String]) -> i32 {
+    if files.is_empty() {
+        eprintln!("Usage: dockerfile-plus lint <file>...");
+        return 2;
+    }
+
+    let mut found = false;
+    for file in files {
+        let contents = match std::fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                return 2;
+            }
+        };
+        for diagnostic in lint_file(file, &contents) {
+            println!("{}", diagnostic);
+            found = true;
+        }
+    }
+
+    if found {
+        1
+    } else {
+        0
+    }
+}
+
+#[test]
+fn typos_and_duplicates() {
+    let contents =
+        "FROM alpine\nINCLUED+ a\nINCLUDE + a\nINCLUDE+ b\nINCLUDE+ b\nINCLUDE+ --bogus c\n";
+    let messages = lint_file("Dockerfile", contents)
+        .into_iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        messages,
+        vec![
+            "Dockerfile:2: Unknown directive \"INCLUED+\", did you mean \"INCLUDE+\"?",
+            "Dockerfile:3: \"INCLUDE +\" is not a directive, did you mean \"INCLUDE+\"?",
+            "Dockerfile:5: \"b\" is included more than once (first at line 4)",
+            "Dockerfile:6: Unknown INCLUDE+ flag \"--bogus\"",
+        ]
+    );
+}
+
+#[test]
+fn ignores_comments_and_continuations() {
+    let contents = "# INCLUED+ a\nRUN apt-get install \\\n    g++ \\\n    foo+\nINCLUDE+ a\n";
+
+    assert_eq!(lint_file("Dockerfile", contents), vec![]);
+}
@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind};
+use crate::include::{sha256_hex, Include};
+
+/// Name of the lockfile, relative to the root of the build context.
+pub const LOCKFILE_NAME: &str = "dockerfile-plus.lock";
+
+/// Value of the `dockerfile-plus.lock` frontend option.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    /// Resolve every remote include and return the refreshed lockfile next to the build result.
+    Update,
+    /// Fail the build if any remote include doesn't match the lockfile.
+    Verify,
+    #[default]
+    Off,
+}
+
+/// Resolved digests of every git, http and image include, keyed by [`Include::lock_key`].
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lockfile {
+    pub includes: BTreeMap<String, String>,
+    /// Commits of git includes and pinned references of image includes, which verified builds
+    /// read them at.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub references: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    pub fn from_slice(bytes: &[u8]) -> Result<Lockfile> {
+        serde_json::from_slice(bytes).with_context(|| format!("Could not parse {}", LOCKFILE_NAME))
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut bytes = serde_json::to_vec_pretty(self)?;
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+}
+
+/// Tracks the remote includes resolved while expanding a Dockerfile.
+pub struct Lock {
+    mode: LockMode,
+    locked: Lockfile,
+    resolved: Lockfile,
+}
+
+impl Lock {
+    pub fn new(mode: LockMode, locked: Lockfile) -> Lock {
+        Lock {
+            mode,
+            locked,
+            resolved: Lockfile::default(),
+        }
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Reference to read a git or image include at, if the lockfile is verified and has one.
+    pub fn pinned(&self, include: &Include) -> Option<String> {
+        match (self.mode, include.lock_key()) {
+            (LockMode::Verify, Some(key)) => self.locked.references.get(&key).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Records the contents of an include, and the reference they were read at, and, in
+    /// verify mode, checks them against the lockfile.
+    pub fn check(
+        &mut self,
+        include: &Include,
+        bytes: &[u8],
+        reference: Option<&str>,
+    ) -> Result<()> {
+        let key = match (self.mode, include.lock_key()) {
+            (LockMode::Off, _) | (_, None) => return Ok(()),
+            (_, Some(key)) => key,
+        };
+        let actual = format!("sha256:{}", sha256_hex(bytes));
+
+        if self.mode == LockMode::Verify {
+            match self.locked.includes.get(&key) {
+                Some(expected) if expected == &actual => {}
+                Some(expected) => bail!(Error::new(
+                    ErrorKind::Mismatch,
+                    format!(
+                        "{} is out of date: \"{}\" resolved to {} but the lockfile expects {}. Build with dockerfile-plus.lock=update to refresh it.",
+                        LOCKFILE_NAME,
+                        key,
+                        actual,
+                        expected
+                    )
+                )),
+                None => bail!(Error::new(
+                    ErrorKind::Mismatch,
+                    format!(
+                        "{} has no entry for \"{}\". Build with dockerfile-plus.lock=update to refresh it.",
+                        LOCKFILE_NAME,
+                        key
+                    )
+                )),
+            }
+        }
+
+        if let Some(reference) = reference {
+            self.resolved
+                .references
+                .insert(key.clone(), reference.to_string());
+        }
+        self.resolved.includes.insert(key, actual);
+        Ok(())
+    }
+
+    /// Keeps the lockfile entry of an include skipped for the platform being built, so
+    /// building a subset of the platforms doesn't make it stale.
+    pub fn skip(&mut self, include: &Include) {
+        if let Some(key) = include.lock_key() {
+            if let Some(reference) = self.locked.references.get(&key) {
+                self.resolved
+                    .references
+                    .insert(key.clone(), reference.clone());
+            }
+            if let Some(locked) = self.locked.includes.get(&key) {
+                self.resolved.includes.insert(key, locked.clone());
+            }
+        }
+    }
+
+    /// Fails in verify mode if the lockfile lists includes that are no longer used.
+    pub fn finish(self) -> Result<Lockfile> {
+        if self.mode == LockMode::Verify {
+            if let Some(stale) = self
+                .locked
+                .includes
+                .keys()
+                .find(|key| !self.resolved.includes.contains_key(*key))
+            {
+                bail!(Error::new(
+                    ErrorKind::Mismatch,
+                    format!(
+                        "{} lists \"{}\", which is no longer included. Build with dockerfile-plus.lock=update to refresh it.",
+                        LOCKFILE_NAME,
+                        stale
+                    )
+                ));
+            }
+        }
+        Ok(self.resolved)
+    }
+}
+
+#[test]
+fn verifies_includes_against_the_lockfile() {
+    let include = Include::parse("https://example.com/common.Dockerfile").unwrap();
+    let locked = |digest: &str| Lockfile {
+        includes: vec![(include.lock_key().unwrap(), digest.to_string())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let digest = format!("sha256:{}", sha256_hex(b"RUN echo common"));
+
+    let mut lock = Lock::new(LockMode::Verify, locked(&digest));
+    lock.check(&include, b"RUN echo common", None).unwrap();
+    lock.finish().unwrap();
+
+    let mut lock = Lock::new(LockMode::Verify, locked("sha256:0000"));
+    let error = lock.check(&include, b"RUN echo common", None).unwrap_err();
+    assert!(error.to_string().contains(&format!(
+        "\"https://example.com/common.Dockerfile\" resolved to {} but the lockfile expects sha256:0000",
+        digest
+    )));
+
+    let mut lock = Lock::new(LockMode::Verify, Lockfile::default());
+    let error = lock.check(&include, b"RUN echo common", None).unwrap_err();
+    assert!(error.to_string().contains("has no entry for"));
+
+    // Entries of includes that are gone fail the build, once every include was checked.
+    let mut stale = locked(&digest);
+    stale.includes.insert(
+        "https://example.com/old.Dockerfile".to_string(),
+        digest.clone(),
+    );
+    let mut lock = Lock::new(LockMode::Verify, stale);
+    lock.check(&include, b"RUN echo common", None).unwrap();
+    let error = lock.finish().unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("lists \"https://example.com/old.Dockerfile\", which is no longer included"));
+}
+
+#[test]
+fn updates_the_lockfile() {
+    let include = Include::parse("https://example.com/common.Dockerfile").unwrap();
+    let context_include = Include::parse("common.Dockerfile").unwrap();
+    let outdated = Lockfile {
+        includes: vec![
+            (include.lock_key().unwrap(), "sha256:0000".to_string()),
+            (
+                "https://example.com/old.Dockerfile".to_string(),
+                "sha256:0000".to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+
+    let mut lock = Lock::new(LockMode::Update, outdated);
+    lock.check(&include, b"RUN echo common", None).unwrap();
+    lock.check(&context_include, b"RUN echo context", None)
+        .unwrap();
+    let bytes = lock.finish().unwrap().to_vec().unwrap();
+
+    // Only the includes still in use are kept, with their current digests.
+    let updated = Lockfile::from_slice(&bytes).unwrap();
+    assert_eq!(updated.includes.len(), 1);
+    let mut lock = Lock::new(LockMode::Verify, updated);
+    lock.check(&include, b"RUN echo common", None).unwrap();
+    lock.check(&context_include, b"RUN echo context", None)
+        .unwrap();
+    lock.finish().unwrap();
+}
+
+#[test]
+fn pins_the_references_of_includes() {
+    let include =
+        Include::parse("--from=https://github.com/example/docker.git#main common.Dockerfile")
+            .unwrap();
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+
+    let mut lock = Lock::new(LockMode::Update, Lockfile::default());
+    assert_eq!(lock.pinned(&include), None);
+    lock.check(&include, b"RUN echo common", Some(commit))
+        .unwrap();
+    let lockfile = lock.finish().unwrap();
+    assert_eq!(lockfile.references[&include.lock_key().unwrap()], commit);
+
+    // Verified builds read the include at the locked commit, wherever the branch is now.
+    let lock = Lock::new(LockMode::Verify, lockfile);
+    assert_eq!(lock.pinned(&include).as_deref(), Some(commit));
+}
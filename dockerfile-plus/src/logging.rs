@@ -0,0 +1,79 @@
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+/// Targets of the logs of dockerfile-plus itself, and of the gateway requests it sends, as
+/// opposed to the ones of its other dependencies.
+const OWN_TARGETS: &[&str] = &["dockerfile_plus", "buildkit_frontend"];
+
+/// Value of the `dockerfile-plus.debug` frontend option.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    /// Lint findings. The default.
+    #[serde(alias = "false")]
+    Warn,
+    /// Also every include, and every build of the expanded Dockerfile.
+    Info,
+    /// Also every gateway request, how long it took and whether it failed. What a bare
+    /// `dockerfile-plus.debug` flag enables.
+    #[serde(alias = "true", alias = "")]
+    Debug,
+    /// Also the contents of gateway requests and responses, with secrets redacted.
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Logs to stderr. `RUST_LOG`, when set, configures every log as usual. Otherwise, logs of
+/// dependencies are shown from `warn` up, and `set_level` decides for the logs of dockerfile-plus.
+pub fn init() {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Warn);
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => {
+            builder.parse_filters(&filters);
+            builder.init();
+        }
+        Err(_) => {
+            for target in OWN_TARGETS {
+                builder.filter_module(target, LevelFilter::Trace);
+            }
+            builder.init();
+            log::set_max_level(LevelFilter::Warn);
+        }
+    }
+}
+
+/// Shows the logs of dockerfile-plus from `level` up, unless `RUST_LOG` is set.
+pub fn set_level(level: LogLevel) {
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(level.into());
+    }
+}
+
+#[test]
+fn parses_the_debug_level() {
+    let debug = |option: &str| {
+        let options: crate::options::DockerfileOptions = buildkit_frontend::options::from_env(
+            vec![("BUILDKIT_FRONTEND_OPT_0".to_string(), option.to_string())],
+        )
+        .unwrap();
+        options.debug
+    };
+    assert_eq!(debug("dockerfile-plus.debug=true"), Some(LogLevel::Debug));
+    assert_eq!(debug("dockerfile-plus.debug"), Some(LogLevel::Debug));
+    assert_eq!(debug("dockerfile-plus.debug="), Some(LogLevel::Debug));
+    assert_eq!(debug("dockerfile-plus.debug=false"), Some(LogLevel::Warn));
+    assert_eq!(debug("dockerfile-plus.debug=trace"), Some(LogLevel::Trace));
+}
@@ -1,143 +1,831 @@
-use std::path::PathBuf;
+#![cfg_attr(nightly, feature(test))]
+
+use std::collections::HashMap;
 
 use dockerfile_frontend::DockerfileFrontend;
+use error::{Error, ErrorKind};
+use expand::Expansion;
+use lock::{Lock, LockMode, Lockfile, LOCKFILE_NAME};
+use logging::LogLevel;
+use options::DockerfileOptions;
+use platform::Platform;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use buildkit_frontend::caps::{CAP_FILE_BASE, CAP_FRONTEND_INPUTS, CAP_RETURN_MAP};
+use buildkit_frontend::{options as frontend_options, Bridge, Frontend, Reference, Transport};
 use buildkit_llb::prelude::*;
 use buildkit_proto::{
     google::rpc::Status,
-    moby::buildkit::v1::frontend::{
-        llb_bridge_client::LlbBridgeClient, result::Result as RefResult, FileRange,
-        ReadFileRequest, ReturnRequest, SolveRequest,
-    },
+    moby::buildkit::v1::frontend::{Ref, ReturnRequest},
+    pb,
 };
-use serde::Deserialize;
-use tonic::{transport::Channel, transport::Endpoint};
-use tower::service_fn;
-
-use futures::executor;
+use log::{debug, error, log_enabled, Level};
 
+mod confine;
 mod dockerfile_frontend;
+mod error;
+mod expand;
+mod include;
+mod lint;
+mod lock;
+mod logging;
+mod matrix;
+#[cfg(test)]
+mod mock_bridge;
 mod options;
-mod stdio;
+mod platform;
+mod provenance;
+mod recording;
+mod subrequests;
+mod targets;
 
-async fn read_file<P>(
-    client: &mut LlbBridgeClient<Channel>,
-    layer: &str,
-    path: P,
-    range: Option<FileRange>,
-) -> Result<Vec<u8>>
-where
-    P: Into<PathBuf>,
-{
-    let file_path = path.into().display().to_string();
+#[cfg(all(test, nightly))]
+extern crate test;
 
-    let request = ReadFileRequest {
-        r#ref: layer.to_string(),
-        file_path,
-        range,
-    };
+/// Returns a scratch layer containing the given files, instead of building the Dockerfile.
+async fn return_files(bridge: &mut Bridge, files: Vec<(&str, Vec<u8>)>) -> Result<ReturnRequest> {
+    let layer = solve_files(bridge, files).await?;
+    Ok(ReturnRequest {
+        result: Some(layer.into_result()),
+        error: None,
+    })
+}
 
-    let response = client.read_file(request).await?.into_inner().data;
+/// Solves a scratch layer containing the given files.
+async fn solve_files(bridge: &mut Bridge, files: Vec<(&str, Vec<u8>)>) -> Result<Reference> {
+    let mut sequence = FileSystem::sequence();
+    for (index, (name, data)) in files.into_iter().enumerate() {
+        let index = index as u32;
+        let layer = match index {
+            0 => LayerPath::Scratch(name),
+            _ => LayerPath::Own(OwnOutputIdx(index - 1), name),
+        };
+        sequence = sequence.append(FileSystem::mkfile(OutputIdx(index), layer).data(data));
+    }
 
-    Ok(response)
+    let output = sequence
+        .last_output()
+        .context("There are no files to return")?;
+    bridge.solve(Terminal::with(output)).await
 }
 
-async fn solve(client: &mut LlbBridgeClient<Channel>, graph: Terminal<'_>) -> Result<String> {
-    let solve_request = SolveRequest {
-        definition: Some(graph.into_definition()),
-        exporter_attr: vec![],
-        allow_result_return: true,
-        ..Default::default()
-    };
-    let temp_result = client
-        .solve(solve_request)
-        .await?
-        .into_inner()
-        .result
-        .unwrap()
-        .result
-        .unwrap();
-    match temp_result {
-        RefResult::RefDeprecated(inner) => Ok(inner),
-        _ => panic!("Unexpected result"),
+/// Solves the frontend input called `name` or, if there isn't one, the local source with that name.
+async fn input_or_local(
+    bridge: &mut Bridge,
+    inputs: &HashMap<String, pb::Definition>,
+    name: &str,
+) -> Result<Reference> {
+    match inputs.get(name) {
+        Some(definition) => bridge.solve_definition(definition.clone()).await,
+        None => {
+            bridge
+                .solve(Terminal::with(Source::local(name).output()))
+                .await
+        }
     }
 }
 
-async fn run(mut client: LlbBridgeClient<Channel>) -> Result<ReturnRequest> {
-    let o: DockerfileOptions = options::from_env(std::env::vars())?;
-    let dockerfile_path = o
-        .filename
-        .as_ref()
-        .and_then(|p| p.to_str())
-        .unwrap_or("Dockerfile");
-    let dockerfile_source = Source::local("dockerfile");
-    let dockerfile_layer = solve(&mut client, Terminal::with(dockerfile_source.output())).await?;
-    let dockerfile_contents =
-        String::from_utf8(read_file(&mut client, &dockerfile_layer, dockerfile_path, None).await?)?;
-    let dockerfile_frontend = DockerfileFrontend::new(client.clone(), dockerfile_path);
-    dockerfile_trap(client.clone(), dockerfile_frontend, dockerfile_contents).await
+/// Reads the Dockerfile at `dockerfile_path`, and solves the build context, from the frontend
+/// inputs or the local sources the options select.
+async fn read_sources(
+    bridge: &mut Bridge,
+    o: &DockerfileOptions,
+    dockerfile_path: &str,
+) -> Result<(String, Reference)> {
+    let inputs = bridge.inputs().await?;
+
+    let dockerfile_name = o
+        .dockerfile_key
+        .as_deref()
+        .unwrap_or(DEFAULT_LOCAL_NAME_DOCKERFILE);
+    let dockerfile_layer = input_or_local(bridge, &inputs, dockerfile_name).await?;
+    let dockerfile_contents = String::from_utf8(
+        bridge
+            .read_file(&dockerfile_layer, dockerfile_path, None)
+            .await?,
+    )?;
+
+    let input_context = o
+        .context
+        .as_deref()
+        .and_then(|context| context.strip_prefix(INPUT_CONTEXT_PREFIX));
+    if input_context.is_some() {
+        bridge
+            .capabilities()
+            .require(CAP_FRONTEND_INPUTS, "context=input:<name>")?;
+    }
+    let context_layer = match input_context {
+        Some(name) => match inputs.get(name) {
+            Some(definition) => bridge.solve_definition(definition.clone()).await?,
+            None => bail!(
+                "The build context was set to the frontend input \"{}\", but there is no input with that name",
+                name
+            ),
+        },
+        None => {
+            let context_name = o
+                .context_key
+                .as_deref()
+                .unwrap_or(DEFAULT_LOCAL_NAME_CONTEXT);
+            input_or_local(bridge, &inputs, context_name).await?
+        }
+    };
+    Ok((dockerfile_contents, context_layer))
+}
+
+/// The Dockerfile+ frontend.
+struct DockerfilePlus;
+
+#[tonic::async_trait]
+impl Frontend for DockerfilePlus {
+    async fn run(&self, mut bridge: Bridge) -> Result<ReturnRequest> {
+        let o: DockerfileOptions = frontend_options::from_env(std::env::vars())
+            .map_err(|e| error::classify(e, ErrorKind::InvalidOption))?;
+        logging::set_level(o.debug.unwrap_or(LogLevel::Warn));
+        if log_enabled!(Level::Debug) {
+            let frontend_opt = format!("{:?}", frontend_options::to_frontend_opt(&o)?);
+            debug!(
+                "Options: {}",
+                buildkit_frontend::logging::redact(&frontend_opt)
+            );
+        }
+        let dockerfile_path = o
+            .filename
+            .as_ref()
+            .and_then(|p| p.to_str())
+            .unwrap_or("Dockerfile");
+        let (dockerfile_contents, context_layer) =
+            read_sources(&mut bridge, &o, dockerfile_path).await?;
+
+        let dockerfile_frontend =
+            DockerfileFrontend::new(bridge.client().clone(), dockerfile_path, o.clone());
+        dockerfile_trap(
+            bridge,
+            dockerfile_frontend,
+            dockerfile_contents,
+            context_layer,
+            &o,
+        )
+        .await
+    }
+
+    fn error_status(&self, error: &anyhow::Error) -> Status {
+        error::to_status(error)
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    let channel = {
-        Endpoint::from_static("http://[::]:50051")
-            .connect_with_connector(service_fn(stdio::stdio_connector))
-            .await
-            .unwrap()
+    logging::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("lint") {
+        std::process::exit(lint::run_cli(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("replay") {
+        std::process::exit(recording::run_cli(&args[1..]).await);
+    }
+    let client = match Transport::from_env() {
+        Ok(transport) => transport.connect().await,
+        Err(e) => Err(e),
+    }
+    .context("Could not connect to BuildKit");
+    // Without a connection to BuildKit, stderr is the only place left to report errors.
+    let result = match client {
+        Ok(client) => match recording::record_if_requested(client).await {
+            Ok(client) => buildkit_frontend::run_with(&DockerfilePlus, Bridge::new(client)).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
     };
-    let mut client = LlbBridgeClient::new(channel);
-    let result = run(client.clone()).await.unwrap_or_else(|e| ReturnRequest {
-        result: None,
-        error: Some(Status {
-            code: 128,
-            message: e.to_string(),
-            details: vec![],
-        }),
-    });
-    client.r#return(result).await.unwrap();
+    if let Err(e) = result {
+        error!("{:#}", e);
+        std::process::exit(1);
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct DockerfileOptions {
-    filename: Option<PathBuf>,
-}
+/// Default names of the local sources, and of the frontend inputs that replace them.
+const DEFAULT_LOCAL_NAME_DOCKERFILE: &str = "dockerfile";
+const DEFAULT_LOCAL_NAME_CONTEXT: &str = "context";
 
-const INCLUDE_COMMAND: &str = "INCLUDE+";
+/// Prefix of the `context` option when the build context is a frontend input.
+const INPUT_CONTEXT_PREFIX: &str = "input:";
+
+/// Names of the files returned with `dockerfile-plus.expand-only`.
+const EXPANDED_DOCKERFILE_NAME: &str = "Dockerfile";
+const INCLUDE_MAP_NAME: &str = "include-map.json";
+
+/// Key of the refreshed lockfile in the result of a `dockerfile-plus.lock=update` build.
+const LOCKFILE_RESULT_ID: &str = "lockfile";
 
 async fn dockerfile_trap(
-    mut client: LlbBridgeClient<Channel>,
+    mut bridge: Bridge,
     dockerfile_frontend: DockerfileFrontend,
     dockerfile_contents: String,
+    context_layer: Reference,
+    options: &DockerfileOptions,
 ) -> Result<ReturnRequest> {
-    let mut result: Vec<String> = vec![];
-    let context_source = Source::local("context");
-    let context_layer = solve(&mut client, Terminal::with(context_source.output())).await?;
-
-    fn replace(
-        l   : &    String,
-        r   : &mut Vec<String>,
-        c   : &mut LlbBridgeClient<Channel>,
-        ctx : &    String
-    )  -> Result<()> {
-        if let Some(file_path) = l.trim().strip_prefix(INCLUDE_COMMAND) {
-            let bytes = executor::block_on(read_file(c, &ctx, file_path.trim_start().to_string(), None))
-                .with_context(|| format!("Could not read file \"{}\". Remember that the file path is relative to the build context, not the Dockerfile path.", file_path))?;
-            //recurse
-            for l2 in std::str::from_utf8(&bytes)?.to_string().lines() {
-                replace(&l2.to_string(), r, c, &ctx)? ;
+    let locked = match options.lock {
+        LockMode::Verify => Lockfile::from_slice(
+            &bridge.read_file(&context_layer, LOCKFILE_NAME, None)
+                .await
+                .with_context(|| format!("Could not read {} from the build context. Build with dockerfile-plus.lock=update to create it.", LOCKFILE_NAME))?,
+        )?,
+        // Entries of includes skipped for the platforms not being built are carried over.
+        LockMode::Update => match bridge.read_file(&context_layer, LOCKFILE_NAME, None).await {
+            Ok(bytes) => Lockfile::from_slice(&bytes)?,
+            Err(e) if error::is_not_found(&e) => Lockfile::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Could not read {} from the build context", LOCKFILE_NAME))
             }
-        } else {
-            r.push(l.to_string());
+        },
+        LockMode::Off => Lockfile::default(),
+    };
+    let mut lock = Lock::new(options.lock, locked);
+
+    let platforms = match &options.platform {
+        Some(specs) if !specs.is_empty() => specs
+            .iter()
+            .map(|spec| Platform::parse(spec))
+            .collect::<Result<Vec<_>>>()?,
+        _ => vec![Platform::default()],
+    };
+    if options.targets.is_some() && platforms.len() > 1 {
+        bail!(Error::new(
+            ErrorKind::InvalidOption,
+            "dockerfile-plus.targets can't be combined with several platforms, build each platform separately",
+        ));
+    }
+
+    let mut expansions = vec![];
+    for platform in platforms {
+        let mut expansion = Expansion::new(
+            &mut bridge,
+            &context_layer,
+            &mut lock,
+            options.limits(),
+            options.lint,
+            &platform,
+        );
+        expansion
+            .expand(dockerfile_frontend.dockerfile_name(), &dockerfile_contents)
+            .await?;
+        let expanded = expansion.finish();
+        expansions.push((platform, expanded));
+    }
+    // Only Dockerfiles with platform-specific includes need a build per platform.
+    let platform_specific = expansions
+        .iter()
+        .any(|(_, expanded)| expanded.dockerfile != expansions[0].1.dockerfile);
+    let expanded = &expansions[0].1;
+
+    if let Some(request_id) = &options.request_id {
+        return Ok(ReturnRequest {
+            result: Some(subrequests::handle(
+                request_id,
+                expanded,
+                options.target.as_deref(),
+            )?),
+            error: None,
+        });
+    }
+
+    // The refreshed lockfile is returned next to the build result, so it can be exported with
+    // `--output`.
+    let lockfile = match lock.mode() {
+        LockMode::Update => Some(lock.finish()?.to_vec()?),
+        _ => {
+            lock.finish()?;
+            None
+        }
+    };
+
+    if options.expand_only {
+        bridge
+            .capabilities()
+            .require(CAP_FILE_BASE, "dockerfile-plus.expand-only")?;
+        let include_map = serde_json::to_vec_pretty(&expanded.include_map)?;
+        let mut files = vec![
+            (
+                EXPANDED_DOCKERFILE_NAME,
+                expanded.dockerfile.clone().into_bytes(),
+            ),
+            (INCLUDE_MAP_NAME, include_map),
+        ];
+        files.extend(lockfile.map(|lockfile| (LOCKFILE_NAME, lockfile)));
+        return return_files(&mut bridge, files).await;
+    }
+    if lockfile.is_some() {
+        for cap in &[CAP_FILE_BASE, CAP_RETURN_MAP] {
+            bridge
+                .capabilities()
+                .require(cap, "dockerfile-plus.lock=update")?;
         }
-        Ok(())
     }
 
-    for line in dockerfile_contents.lines() {
-        replace(&line.to_string(), &mut result, &mut client, &context_layer)? ;
+    if platform_specific {
+        if options.targets.is_some() {
+            bail!("dockerfile-plus.targets can't be combined with platform-specific INCLUDE+ directives");
+        }
+        bridge
+            .capabilities()
+            .require(CAP_RETURN_MAP, "platform-specific INCLUDE+ directives")?;
+        let mut results = vec![];
+        for (platform, expanded) in &expansions {
+            let platform_options = DockerfileOptions {
+                platform: Some(vec![platform.to_string()]),
+                ..options.clone()
+            };
+            let mut result = dockerfile_frontend
+                .solve_with_options(&expanded.dockerfile, &platform_options)
+                .await?;
+            provenance::attach(
+                &mut result,
+                &expanded.include_map.includes,
+                options.provenance_labels,
+            )?;
+            results.push((platform.clone(), result));
+        }
+        let result = platform::combine(results)?;
+        return add_lockfile(&mut bridge, result, &expansions[0].0, lockfile).await;
     }
-    let dockerfile_contents = result.join("\n");
-    dockerfile_frontend.solve(&dockerfile_contents).await
+
+    let mut result = match &options.targets {
+        Some(targets) => {
+            bridge
+                .capabilities()
+                .require(CAP_RETURN_MAP, "dockerfile-plus.targets")?;
+            targets::solve_targets(&dockerfile_frontend, &expanded.dockerfile, targets).await?
+        }
+        None => dockerfile_frontend.solve(&expanded.dockerfile).await?,
+    };
+    provenance::attach(
+        &mut result,
+        &expanded.include_map.includes,
+        options.provenance_labels,
+    )?;
+    add_lockfile(&mut bridge, result, &expansions[0].0, lockfile).await
+}
+
+/// Adds the refreshed lockfile, if there is one, to the result of a build for `platform`.
+async fn add_lockfile(
+    bridge: &mut Bridge,
+    result: ReturnRequest,
+    platform: &Platform,
+    lockfile: Option<Vec<u8>>,
+) -> Result<ReturnRequest> {
+    let lockfile = match lockfile {
+        Some(lockfile) => lockfile,
+        None => return Ok(result),
+    };
+    let layer = solve_files(bridge, vec![(LOCKFILE_NAME, lockfile)]).await?;
+    let entry = Ref {
+        id: layer.id().to_string(),
+        def: None,
+    };
+    platform::add_entry(result, platform, LOCKFILE_RESULT_ID, platform, entry)
+}
+
+#[cfg(test)]
+fn layer(id: &str) -> buildkit_proto::moby::buildkit::v1::frontend::Result {
+    Reference::new(id).into_result()
+}
+
+#[tokio::test]
+async fn returns_gateway_failures_as_errors() {
+    use mock_bridge::MockBridge;
+
+    // A solve without a result fails the build with a message.
+    let bridge = MockBridge::default();
+    let returned = bridge.returned.clone();
+    buildkit_frontend::run_with(&DockerfilePlus, Bridge::new(bridge.connect().await))
+        .await
+        .unwrap();
+    let error = returned.lock().unwrap()[0].error.clone().unwrap();
+    assert!(error.message.contains("The solve returned no result"));
+
+    // So does a Dockerfile that can't be read.
+    let bridge = MockBridge {
+        solve_result: Some(layer("layer")),
+        ..Default::default()
+    };
+    let returned = bridge.returned.clone();
+    buildkit_frontend::run_with(&DockerfilePlus, Bridge::new(bridge.connect().await))
+        .await
+        .unwrap();
+    let error = returned.lock().unwrap()[0].error.clone().unwrap();
+    assert_eq!(error.code, tonic::Code::NotFound as i32);
+    assert!(error.message.contains("Dockerfile: no such file"));
+
+    // Returning nothing is an error too.
+    let mut bridge = Bridge::new(MockBridge::default().connect().await);
+    assert!(return_files(&mut bridge, vec![]).await.is_err());
+
+    // Failing to return the result is reported by the caller.
+    let bridge = MockBridge {
+        fail_return: true,
+        ..Default::default()
+    };
+    let error = buildkit_frontend::run_with(&DockerfilePlus, Bridge::new(bridge.connect().await))
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", error).contains("the build was cancelled"));
+}
+
+/// Builds every target into a ref named after it, as the Dockerfile frontend would.
+#[cfg(test)]
+fn fake_build(options: &DockerfileOptions) -> buildkit_proto::moby::buildkit::v1::frontend::Result {
+    let target = options.target.as_deref().unwrap_or("default");
+    let mut result = layer(&format!("image-{}", target));
+    result.metadata.insert(
+        "containerimage.config".to_string(),
+        br#"{"config":{}}"#.to_vec(),
+    );
+    result
+}
+
+/// Runs Dockerfile+ on the Dockerfile of `bridge`, with `fake_build` as the Dockerfile frontend.
+#[cfg(test)]
+async fn build(
+    bridge: mock_bridge::MockBridge,
+    options: DockerfileOptions,
+) -> Result<ReturnRequest> {
+    let mut bridge = Bridge::new(bridge.connect().await);
+    bridge.ping().await?;
+    let context = Reference::new("context");
+    let contents = String::from_utf8(bridge.read_file(&context, "Dockerfile", None).await?)?;
+    let frontend = DockerfileFrontend::new(bridge.client().clone(), "Dockerfile", options.clone())
+        .with_fake(fake_build);
+    dockerfile_trap(bridge, frontend, contents, context, &options).await
+}
+
+#[cfg(test)]
+async fn expand_only(bridge: mock_bridge::MockBridge) -> Result<HashMap<String, Vec<u8>>> {
+    let solves = bridge.solves.clone();
+    let options = DockerfileOptions {
+        expand_only: true,
+        ..frontend_options::from_env(vec![])?
+    };
+    build(bridge, options).await?;
+    let solves = solves.lock().unwrap();
+    Ok(mock_bridge::created_files(&solves))
+}
+
+#[tokio::test]
+async fn expands_includes_from_the_build_context() {
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("expanded")),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ docker/current.Dockerfile\n",
+    )
+    .with_symlink("docker/current.Dockerfile", "common.Dockerfile")
+    .with_file("docker/common.Dockerfile", "RUN echo common\n");
+
+    let files = expand_only(bridge).await.unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&files[EXPANDED_DOCKERFILE_NAME]),
+        "FROM alpine\nRUN echo common"
+    );
+    let include_map: serde_json::Value = serde_json::from_slice(&files[INCLUDE_MAP_NAME]).unwrap();
+    assert_eq!(
+        include_map["includes"][0]["include"],
+        "docker/current.Dockerfile"
+    );
+}
+
+#[tokio::test]
+async fn reports_includes_that_leave_the_build_context() {
+    let bridge = mock_bridge::MockBridge::default()
+        .with_file("Dockerfile", "FROM alpine\nINCLUDE+ escape.Dockerfile\n")
+        .with_symlink("escape.Dockerfile", "../secrets/Dockerfile");
+    let error = expand_only(bridge).await.unwrap_err();
+    let status = error::to_status(&error);
+    assert_eq!(status.code, tonic::Code::PermissionDenied as i32);
+    assert!(status.message.starts_with("Dockerfile:2: "));
+
+    let bridge = mock_bridge::MockBridge::default()
+        .with_file("Dockerfile", "FROM alpine\nINCLUDE+ missing.Dockerfile\n");
+    let status = error::to_status(&expand_only(bridge).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::NotFound as i32);
+}
+
+#[tokio::test]
+async fn reports_failed_fetches_with_the_gateway_code() {
+    let bridge = mock_bridge::MockBridge {
+        fail_solve: Some(tonic::Code::Unavailable),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ https://example.com/common.Dockerfile\n",
+    );
+    let status = error::to_status(&expand_only(bridge).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::Unavailable as i32);
+    assert!(status.message.starts_with("Dockerfile:2: "));
+
+    // A git checkout that isn't at a commit is no missing include either.
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("layer")),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ --from=https://github.com/example/docker.git common.Dockerfile\n",
+    )
+    .with_file("common.Dockerfile", "RUN echo common\n")
+    .with_file(".git/HEAD", "garbage\n");
+    let status = error::to_status(&expand_only(bridge).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::Unknown as i32);
+    assert!(status.message.contains("Could not resolve the commit"));
+}
+
+#[tokio::test]
+async fn reports_include_cycles_however_paths_are_written() {
+    let bridge = mock_bridge::MockBridge::default()
+        .with_file("Dockerfile", "FROM alpine\nINCLUDE+ ./a.Dockerfile\n")
+        .with_file("a.Dockerfile", "INCLUDE+ sub/../b.Dockerfile\n")
+        .with_file("sub/b.Dockerfile", "")
+        .with_file("b.Dockerfile", "INCLUDE+ a.Dockerfile\n");
+    let status = error::to_status(&expand_only(bridge).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::FailedPrecondition as i32);
+    assert!(
+        status
+            .message
+            .contains("INCLUDE+ cycle: Dockerfile -> a.Dockerfile -> b.Dockerfile -> a.Dockerfile"),
+        "{}",
+        status.message
+    );
+}
+
+#[tokio::test]
+async fn reports_features_buildkit_is_too_old_for() {
+    let capabilities = ["solve.base", "return", "readfile", "statfile", "file.base"];
+    let bridge = mock_bridge::MockBridge {
+        capabilities: Some(capabilities.iter().map(|cap| cap.to_string()).collect()),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ --from=https://github.com/example/docker.git common.Dockerfile\n",
+    );
+    let status = error::to_status(&expand_only(bridge).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::Unimplemented as i32);
+    assert_eq!(
+        status.message,
+        "Dockerfile:2: Your BuildKit is too old for INCLUDE+ --from=<git repository> (needs cap source.git)"
+    );
+}
+
+#[tokio::test]
+async fn returns_the_refreshed_lockfile_next_to_the_build() {
+    use buildkit_proto::moby::buildkit::v1::frontend::result::Result as RefResult;
+
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("layer")),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ https://example.com/common.Dockerfile\n",
+    )
+    .with_file("include", "RUN echo remote\n");
+    let solves = bridge.solves.clone();
+    let options = DockerfileOptions {
+        lock: LockMode::Update,
+        ..frontend_options::from_env(vec![]).unwrap()
+    };
+
+    let result = build(bridge, options).await.unwrap().result.unwrap();
+    let platform = Platform::default().to_string();
+    match result.result {
+        Some(RefResult::Refs(map)) => {
+            assert_eq!(map.refs[&platform].id, "image-default");
+            assert_eq!(map.refs[LOCKFILE_RESULT_ID].id, "layer");
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    // Exporters find the image config of the build, and export each result on its own.
+    assert!(result
+        .metadata
+        .contains_key(&format!("containerimage.config/{}", platform)));
+    let platforms: serde_json::Value =
+        serde_json::from_slice(&result.metadata["refs.platforms"]).unwrap();
+    assert_eq!(platforms["platforms"][0]["id"], platform.as_str());
+    assert_eq!(platforms["platforms"][1]["id"], LOCKFILE_RESULT_ID);
+
+    let files = mock_bridge::created_files(&solves.lock().unwrap());
+    let lockfile = Lockfile::from_slice(&files[LOCKFILE_NAME]).unwrap();
+    assert_eq!(
+        lockfile.includes["https://example.com/common.Dockerfile"],
+        format!("sha256:{}", include::sha256_hex(b"RUN echo remote\n"))
+    );
+}
+
+#[tokio::test]
+async fn returns_each_target_under_its_own_key() {
+    use buildkit_proto::moby::buildkit::v1::frontend::result::Result as RefResult;
+
+    let dockerfile = "FROM alpine AS api\nFROM alpine AS worker\n";
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("layer")),
+        ..Default::default()
+    }
+    .with_file("Dockerfile", dockerfile);
+    let options = DockerfileOptions {
+        targets: Some(vec!["api".to_string(), "worker".to_string()]),
+        lock: LockMode::Update,
+        ..frontend_options::from_env(vec![]).unwrap()
+    };
+
+    let result = build(bridge, options.clone())
+        .await
+        .unwrap()
+        .result
+        .unwrap();
+    match result.result {
+        Some(RefResult::Refs(map)) => {
+            assert_eq!(map.refs["api"].id, "image-api");
+            assert_eq!(map.refs["worker"].id, "image-worker");
+            assert_eq!(map.refs[LOCKFILE_RESULT_ID].id, "layer");
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert!(result.metadata.contains_key("containerimage.config/worker"));
+    // Targets aren't platforms, and aren't listed as such.
+    assert!(!result.metadata.contains_key("refs.platforms"));
+
+    // Each target of a multi-platform build would be a map of platforms of its own.
+    let bridge = mock_bridge::MockBridge::default().with_file("Dockerfile", dockerfile);
+    let options = DockerfileOptions {
+        platform: Some(vec!["linux/amd64".to_string(), "linux/arm64".to_string()]),
+        ..options
+    };
+    let status = error::to_status(&build(bridge, options).await.unwrap_err());
+    assert_eq!(status.code, tonic::Code::InvalidArgument as i32);
+    assert!(status
+        .message
+        .contains("can't be combined with several platforms"));
+}
+
+#[tokio::test]
+async fn updates_the_lockfile_for_a_single_platform() {
+    let arm = "https://example.com/arm.Dockerfile";
+    let locked = Lockfile {
+        includes: vec![(arm.to_string(), "sha256:0000".to_string())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    };
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("layer")),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        format!(
+            "FROM alpine\nINCLUDE+ --platform=linux/arm64 {}\nINCLUDE+ https://example.com/common.Dockerfile\n",
+            arm
+        ),
+    )
+    .with_file("include", "RUN echo remote\n")
+    .with_file(LOCKFILE_NAME, locked.to_vec().unwrap());
+    let solves = bridge.solves.clone();
+    let options = DockerfileOptions {
+        lock: LockMode::Update,
+        platform: Some(vec!["linux/amd64".to_string()]),
+        ..frontend_options::from_env(vec![]).unwrap()
+    };
+
+    build(bridge, options).await.unwrap();
+    let files = mock_bridge::created_files(&solves.lock().unwrap());
+    let lockfile = Lockfile::from_slice(&files[LOCKFILE_NAME]).unwrap();
+    // The include of the platform that wasn't built keeps its entry.
+    assert_eq!(lockfile.includes[arm], "sha256:0000");
+    assert_eq!(
+        lockfile.includes["https://example.com/common.Dockerfile"],
+        format!("sha256:{}", include::sha256_hex(b"RUN echo remote\n"))
+    );
+}
+
+#[tokio::test]
+async fn records_the_commits_of_git_includes() {
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    let bridge = mock_bridge::MockBridge {
+        solve_result: Some(layer("checkout")),
+        ..Default::default()
+    }
+    .with_file(
+        "Dockerfile",
+        "FROM alpine\nINCLUDE+ --from=https://github.com/example/docker.git#main common.Dockerfile\n",
+    )
+    .with_file("common.Dockerfile", "RUN echo common\n")
+    .with_file(".git/HEAD", format!("{}\n", commit));
+
+    let files = expand_only(bridge).await.unwrap();
+    let include_map: serde_json::Value = serde_json::from_slice(&files[INCLUDE_MAP_NAME]).unwrap();
+    assert_eq!(include_map["includes"][0]["reference"], commit);
+}
+
+/// Identifiers of the sources solved by `bridge` for the options, in order.
+#[cfg(test)]
+async fn selected_sources(
+    bridge: mock_bridge::MockBridge,
+    options: Vec<(&str, &str)>,
+) -> Result<Vec<String>> {
+    use buildkit_proto::pb::op::Op;
+    use prost::Message;
+
+    let solves = bridge.solves.clone();
+    let mut bridge = Bridge::new(bridge.connect().await);
+    let options = frontend_options::from_env(options.into_iter().enumerate().map(
+        |(index, (key, value))| {
+            (
+                format!("BUILDKIT_FRONTEND_OPT_{}", index),
+                format!("{}={}", key, value),
+            )
+        },
+    ))?;
+    let (contents, _) = read_sources(&mut bridge, &options, "Dockerfile").await?;
+    assert_eq!(contents, "FROM alpine");
+
+    let solves = solves.lock().unwrap();
+    Ok(solves
+        .iter()
+        .flat_map(|solve| solve.definition.as_ref().unwrap().def.iter())
+        .filter_map(|op| match pb::Op::decode(&op[..]).ok()?.op {
+            Some(Op::Source(source)) => Some(source.identifier),
+            _ => None,
+        })
+        .collect())
+}
+
+#[tokio::test]
+async fn reads_sources_from_frontend_inputs() {
+    use mock_bridge::MockBridge;
+
+    let input = |name: &str| {
+        Terminal::with(Source::local(format!("input-{}", name)).output()).into_definition()
+    };
+    let with_inputs = |names: &[&str]| {
+        MockBridge {
+            solve_result: Some(layer("layer")),
+            inputs: Some(
+                names
+                    .iter()
+                    .map(|name| (name.to_string(), input(name)))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+        .with_file("Dockerfile", "FROM alpine")
+    };
+
+    // Without inputs, the local sources are used.
+    let sources = selected_sources(with_inputs(&[]), vec![]).await.unwrap();
+    assert_eq!(sources, vec!["local://dockerfile", "local://context"]);
+    let sources = selected_sources(
+        MockBridge {
+            solve_result: Some(layer("layer")),
+            ..Default::default()
+        }
+        .with_file("Dockerfile", "FROM alpine"),
+        vec![],
+    )
+    .await
+    .unwrap();
+    assert_eq!(sources, vec!["local://dockerfile", "local://context"]);
+
+    // Inputs named like the local sources replace them.
+    let sources = selected_sources(with_inputs(&["dockerfile", "context"]), vec![])
+        .await
+        .unwrap();
+    assert_eq!(
+        sources,
+        vec!["local://input-dockerfile", "local://input-context"]
+    );
+
+    // So do the inputs named with dockerfilekey and contextkey, which fall back to the local
+    // sources of that name.
+    let keys = vec![("dockerfilekey", "df"), ("contextkey", "ctx")];
+    let sources = selected_sources(with_inputs(&["df", "ctx"]), keys.clone())
+        .await
+        .unwrap();
+    assert_eq!(sources, vec!["local://input-df", "local://input-ctx"]);
+    let sources = selected_sources(with_inputs(&["df"]), keys).await.unwrap();
+    assert_eq!(sources, vec!["local://input-df", "local://ctx"]);
+
+    // context=input:<name> takes the context from that input, which must exist.
+    let context = vec![("context", "input:shared")];
+    let sources = selected_sources(with_inputs(&["shared"]), context.clone())
+        .await
+        .unwrap();
+    assert_eq!(sources, vec!["local://dockerfile", "local://input-shared"]);
+    let error = selected_sources(with_inputs(&[]), context)
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("frontend input \"shared\", but there is no input with that name"));
 }
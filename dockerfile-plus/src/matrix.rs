@@ -0,0 +1,314 @@
+use anyhow::{bail, Result};
+
+use crate::error::{Error, ErrorKind};
+
+pub const MATRIX_COMMAND: &str = "MATRIX+";
+pub const END_MATRIX_COMMAND: &str = "ENDMATRIX+";
+
+/// Arguments of a `MATRIX+` directive:
+/// `MATRIX+ [--aggregate=<stage>] <variable>=<value>,<value>... ...`.
+#[derive(Debug, PartialEq)]
+pub struct Matrix {
+    pub variables: Vec<(String, Vec<String>)>,
+    pub aggregate: Option<String>,
+}
+
+impl Matrix {
+    pub fn parse(args: &str) -> Result<Matrix> {
+        let mut variables: Vec<(String, Vec<String>)> = vec![];
+        let mut aggregate = None;
+
+        for word in args.split_whitespace() {
+            if let Some(flag) = word.strip_prefix("--") {
+                match flag.strip_prefix("aggregate=") {
+                    Some(name) if !name.is_empty() => aggregate = Some(name.to_string()),
+                    _ => bail!(Error::new(
+                        ErrorKind::Parse,
+                        format!("Unknown MATRIX+ flag \"{}\"", word)
+                    )),
+                }
+                continue;
+            }
+
+            let mut parts = word.splitn(2, '=');
+            let name = parts.next().unwrap();
+            let values = match parts.next() {
+                Some(values) if !name.is_empty() && !values.is_empty() => values,
+                _ => bail!(Error::new(
+                    ErrorKind::Parse,
+                    format!(
+                        "Invalid MATRIX+ variable \"{}\": expected <name>=<value>,<value>...",
+                        word
+                    )
+                )),
+            };
+            if variables.iter().any(|(existing, _)| existing == name) {
+                bail!(Error::new(
+                    ErrorKind::Parse,
+                    format!("MATRIX+ variable \"{}\" is defined more than once", name)
+                ));
+            }
+            variables.push((
+                name.to_string(),
+                values.split(',').map(str::to_string).collect(),
+            ));
+        }
+
+        if variables.is_empty() {
+            bail!(Error::new(
+                ErrorKind::Parse,
+                "MATRIX+ requires at least one <name>=<value>,<value>... variable"
+            ));
+        }
+
+        Ok(Matrix {
+            variables,
+            aggregate,
+        })
+    }
+
+    /// Every combination of values, varying the last variable fastest.
+    fn combinations(&self) -> Vec<Vec<(&str, &str)>> {
+        let mut combinations = vec![vec![]];
+        for (name, values) in &self.variables {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination: Vec<(&str, &str)>| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((name.as_str(), value.as_str()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        combinations
+    }
+}
+
+/// A `MATRIX+` block being collected: the line it starts at, its arguments and its body.
+struct Block<'a> {
+    start: usize,
+    matrix: Matrix,
+    body: Vec<(usize, &'a str)>,
+}
+
+/// Replaces every `MATRIX+` ... `ENDMATRIX+` block with one copy of its body per combination
+/// of values. Returns the resulting lines along with the line number they come from.
+pub fn expand_matrices(contents: &str) -> Result<Vec<(usize, String)>> {
+    let mut lines = vec![];
+    let mut block: Option<Block> = None;
+
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let trimmed = line.trim();
+
+        if let Some(args) = trimmed.strip_prefix(MATRIX_COMMAND) {
+            if let Some(block) = &block {
+                bail!(Error::new(
+                    ErrorKind::Parse,
+                    format!(
+                        "MATRIX+ at line {} is inside the MATRIX+ block started at line {}, nested MATRIX+ blocks are not supported",
+                        line_number,
+                        block.start
+                    )
+                ));
+            }
+            block = Some(Block {
+                start: line_number,
+                matrix: Matrix::parse(args)?,
+                body: vec![],
+            });
+        } else if trimmed.starts_with(END_MATRIX_COMMAND) {
+            match block.take() {
+                Some(block) => lines.extend(render(block.start, &block.matrix, &block.body)),
+                None => bail!(Error::new(
+                    ErrorKind::Parse,
+                    format!("ENDMATRIX+ at line {} has no matching MATRIX+", line_number)
+                )),
+            }
+        } else {
+            match &mut block {
+                Some(block) => block.body.push((line_number, line)),
+                None => lines.push((line_number, line.to_string())),
+            }
+        }
+    }
+
+    if let Some(block) = block {
+        bail!(Error::new(
+            ErrorKind::Parse,
+            format!("MATRIX+ at line {} has no matching ENDMATRIX+", block.start)
+        ));
+    }
+
+    Ok(lines)
+}
+
+fn render(start: usize, matrix: &Matrix, body: &[(usize, &str)]) -> Vec<(usize, String)> {
+    let mut lines = vec![];
+    let mut variants = vec![];
+
+    for combination in matrix.combinations() {
+        let suffix = combination
+            .iter()
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>()
+            .join("-");
+        let substituted = body
+            .iter()
+            .map(|(line_number, line)| (*line_number, substitute(line, &combination)))
+            .collect::<Vec<_>>();
+
+        let stages = substituted
+            .iter()
+            .filter_map(|(_, line)| stage_name(line))
+            .collect::<Vec<_>>();
+        for (line_number, line) in &substituted {
+            lines.push((*line_number, rename_stages(line, &stages, &suffix)));
+        }
+        variants.extend(stages.iter().map(|stage| format!("{}-{}", stage, suffix)));
+    }
+
+    // The aggregate stage copies every variant, so building it builds all of them.
+    if let Some(aggregate) = &matrix.aggregate {
+        lines.push((start, format!("FROM scratch AS {}", aggregate)));
+        for variant in variants {
+            lines.push((start, format!("COPY --from={} / /{}/", variant, variant)));
+        }
+    }
+
+    lines
+}
+
+fn substitute(line: &str, combination: &[(&str, &str)]) -> String {
+    combination
+        .iter()
+        .fold(line.to_string(), |line, (name, value)| {
+            line.replace(&format!("${{{}}}", name), value)
+        })
+}
+
+/// Name of the stage started by a `FROM <image> AS <name>` line.
+fn stage_name(line: &str) -> Option<String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        [from, .., as_keyword, name]
+            if from.eq_ignore_ascii_case("FROM") && as_keyword.eq_ignore_ascii_case("AS") =>
+        {
+            Some(name.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Appends `suffix` to every reference to `stages` in `FROM` and `--from=`. The rest of the line,
+/// whitespace included, is kept as is.
+fn rename_stages(line: &str, stages: &[String], suffix: &str) -> String {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let is_from = matches!(words.first(), Some(word) if word.eq_ignore_ascii_case("FROM"));
+    if !is_from && !line.contains("--from=") {
+        return line.to_string();
+    }
+
+    let rename = |name: &str| -> Option<String> {
+        if stages.iter().any(|stage| stage == name) {
+            Some(format!("{}-{}", name, suffix))
+        } else {
+            None
+        }
+    };
+
+    let mut renamed = String::with_capacity(line.len());
+    let mut rest = line;
+    let mut base_seen = false;
+    let mut previous_is_as = false;
+    for (index, word) in words.iter().enumerate() {
+        let new_word = if let Some(name) = word.strip_prefix("--from=") {
+            rename(name).map(|name| format!("--from={}", name))
+        } else if is_from && index > 0 && !word.starts_with("--") && !base_seen {
+            base_seen = true;
+            rename(word)
+        } else if is_from && previous_is_as {
+            rename(word)
+        } else {
+            None
+        };
+        previous_is_as = word.eq_ignore_ascii_case("AS");
+        // Only whitespace comes before the word in what is left of the line.
+        let start = rest.find(word).unwrap();
+        renamed.push_str(&rest[..start]);
+        renamed.push_str(new_word.as_deref().unwrap_or(word));
+        rest = &rest[start + word.len()..];
+    }
+    renamed.push_str(rest);
+    renamed
+}
+
+#[test]
+fn expands_every_combination() {
+    let contents = [
+        "FROM alpine AS common",
+        "MATRIX+ --aggregate=all os=debian,alpine py=3.10,3.11",
+        "FROM python:${py}-${os} AS build",
+        "RUN echo ${os}",
+        "FROM build AS test",
+        "COPY --from=build /app /app",
+        "COPY --from=common /etc /etc",
+        "ENDMATRIX+",
+        "FROM alpine",
+    ]
+    .join("\n");
+
+    let lines = expand_matrices(&contents)
+        .unwrap()
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect::<Vec<_>>();
+
+    assert_eq!(lines[0], "FROM alpine AS common");
+    assert_eq!(
+        &lines[1..6],
+        &[
+            "FROM python:3.10-debian AS build-debian-3.10",
+            "RUN echo debian",
+            "FROM build-debian-3.10 AS test-debian-3.10",
+            "COPY --from=build-debian-3.10 /app /app",
+            "COPY --from=common /etc /etc",
+        ]
+    );
+    assert_eq!(lines[16], "FROM python:3.11-alpine AS build-alpine-3.11");
+    assert_eq!(lines[21], "FROM scratch AS all");
+    assert_eq!(
+        lines[22],
+        "COPY --from=build-debian-3.10 / /build-debian-3.10/"
+    );
+    // One line before the block, 4 combinations of 5 lines, and the aggregate stage copying
+    // both stages of each combination.
+    assert_eq!(lines.len(), 1 + 4 * 5 + 1 + 8 + 1);
+    assert_eq!(lines[30], "FROM alpine");
+}
+
+#[test]
+fn renames_stages_in_place() {
+    let stages = vec!["build".to_string()];
+    assert_eq!(
+        rename_stages("  FROM\tbuild   AS  test ", &stages, "a"),
+        "  FROM\tbuild-a   AS  test "
+    );
+    assert_eq!(
+        rename_stages("COPY  --from=build\t/app   \"/my  app\"", &stages, "a"),
+        "COPY  --from=build-a\t/app   \"/my  app\""
+    );
+    assert_eq!(
+        rename_stages("RUN echo build  &&  echo --from=other", &stages, "a"),
+        "RUN echo build  &&  echo --from=other"
+    );
+}
+
+#[test]
+fn rejects_unbalanced_blocks() {
+    assert!(expand_matrices("MATRIX+ os=a,b\nFROM ${os}").is_err());
+    assert!(expand_matrices("FROM a\nENDMATRIX+").is_err());
+    assert!(expand_matrices("MATRIX+ os=a\nMATRIX+ py=b\nENDMATRIX+\nENDMATRIX+").is_err());
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use buildkit_proto::{
+    fsutil::types::Stat,
+    moby::buildkit::v1::{
+        apicaps::ApiCap,
+        frontend::{self, llb_bridge_client::LlbBridgeClient, llb_bridge_server::LlbBridge},
+    },
+    pb,
+};
+use prost::Message;
+use tonic::{transport::Channel, Request, Response, Status};
+
+use buildkit_frontend::stdio::connect_in_process;
+
+/// `os.ModeDir` and `os.ModeSymlink` from Go, as reported in `fsutil.types.Stat.mode`.
+const MODE_DIR: u32 = 1 << 31;
+const MODE_SYMLINK: u32 = 1 << 27;
+
+/// A fake BuildKit gateway for tests. It serves a filesystem from memory, records the
+/// definitions it is asked to solve and captures the result returned to it.
+#[derive(Default)]
+pub struct MockBridge {
+    /// Contents of the files, by path. Every ref sees the same files.
+    pub files: HashMap<String, Vec<u8>>,
+    /// Targets of the symlinks, by path.
+    pub symlinks: HashMap<String, String>,
+    /// Digests `ResolveImageConfig` resolves images to. Other images are not found.
+    pub images: HashMap<String, String>,
+    /// Frontend inputs. Without them `Inputs` is unimplemented, as in old BuildKit versions.
+    pub inputs: Option<HashMap<String, pb::Definition>>,
+    /// Capabilities answered to `Ping`, all enabled. Without them `Ping` is unimplemented, and
+    /// every capability is assumed.
+    pub capabilities: Option<Vec<String>>,
+    /// Result of every `Solve`.
+    pub solve_result: Option<frontend::Result>,
+    /// How long every `Solve` takes.
+    pub solve_delay: Duration,
+    /// Number of `Solve`s in progress, and the most of them there ever were at once.
+    pub concurrent_solves: Arc<Mutex<(usize, usize)>>,
+    /// Code every `Solve` fails with, if any.
+    pub fail_solve: Option<tonic::Code>,
+    /// Whether `Return` fails.
+    pub fail_return: bool,
+    /// The requests passed to `Solve`.
+    pub solves: Arc<Mutex<Vec<frontend::SolveRequest>>>,
+    /// The requests passed to `Return`.
+    pub returned: Arc<Mutex<Vec<frontend::ReturnRequest>>>,
+}
+
+impl MockBridge {
+    pub fn with_file<C: Into<Vec<u8>>>(mut self, path: &str, contents: C) -> MockBridge {
+        self.files
+            .insert(normalize(path).to_string(), contents.into());
+        self
+    }
+
+    pub fn with_symlink(mut self, path: &str, target: &str) -> MockBridge {
+        self.symlinks
+            .insert(normalize(path).to_string(), target.to_string());
+        self
+    }
+
+    /// Serves this bridge and returns a client connected to it.
+    pub async fn connect(self) -> LlbBridgeClient<Channel> {
+        connect_in_process(self).await.unwrap()
+    }
+
+    fn stat(&self, path: &str) -> Option<Stat> {
+        let path = normalize(path);
+        let is_dir = || {
+            let prefix = format!("{}/", path);
+            path.is_empty() || self.entries().any(|entry| entry.starts_with(&prefix))
+        };
+        let (mode, size, linkname) = if let Some(data) = self.files.get(path) {
+            (0o644, data.len() as i64, String::new())
+        } else if let Some(target) = self.symlinks.get(path) {
+            (MODE_SYMLINK | 0o777, 0, target.clone())
+        } else if is_dir() {
+            (MODE_DIR | 0o755, 0, String::new())
+        } else {
+            return None;
+        };
+        Some(Stat {
+            path: path.to_string(),
+            mode,
+            size,
+            linkname,
+            ..Default::default()
+        })
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &str> {
+        self.files
+            .keys()
+            .chain(self.symlinks.keys())
+            .map(String::as_str)
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches('/');
+    path.strip_prefix("./").unwrap_or(path)
+}
+
+fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(end) => (&path[..end], &path[end + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<_>>();
+    let mut rest = match name.strip_prefix(parts[0]) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    for (index, part) in parts.iter().enumerate().skip(1) {
+        if index == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(start) => rest = &rest[start + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// Files created with `mkfile` by the definitions of `solves`, by path.
+pub fn created_files(solves: &[frontend::SolveRequest]) -> HashMap<String, Vec<u8>> {
+    let mut files = HashMap::new();
+    let ops = solves
+        .iter()
+        .filter_map(|solve| solve.definition.as_ref())
+        .flat_map(|definition| definition.def.iter())
+        .filter_map(|op| pb::Op::decode(&op[..]).ok());
+    for op in ops {
+        if let Some(pb::op::Op::File(file_op)) = op.op {
+            for action in file_op.actions {
+                if let Some(pb::file_action::Action::Mkfile(mkfile)) = action.action {
+                    files.insert(normalize(&mkfile.path).to_string(), mkfile.data);
+                }
+            }
+        }
+    }
+    files
+}
+
+#[tonic::async_trait]
+impl LlbBridge for MockBridge {
+    async fn resolve_image_config(
+        &self,
+        request: Request<frontend::ResolveImageConfigRequest>,
+    ) -> Result<Response<frontend::ResolveImageConfigResponse>, Status> {
+        let image = request.into_inner().r#ref;
+        match self.images.get(&image) {
+            Some(digest) => Ok(Response::new(frontend::ResolveImageConfigResponse {
+                digest: digest.clone(),
+                ..Default::default()
+            })),
+            None => Err(Status::not_found(format!("{}: not found", image))),
+        }
+    }
+
+    async fn solve(
+        &self,
+        request: Request<frontend::SolveRequest>,
+    ) -> Result<Response<frontend::SolveResponse>, Status> {
+        self.solves.lock().unwrap().push(request.into_inner());
+        {
+            let mut concurrent = self.concurrent_solves.lock().unwrap();
+            concurrent.0 += 1;
+            concurrent.1 = concurrent.1.max(concurrent.0);
+        }
+        tokio::time::delay_for(self.solve_delay).await;
+        self.concurrent_solves.lock().unwrap().0 -= 1;
+        if let Some(code) = self.fail_solve {
+            return Err(Status::new(code, "the solve failed"));
+        }
+        Ok(Response::new(frontend::SolveResponse {
+            result: self.solve_result.clone(),
+            ..Default::default()
+        }))
+    }
+
+    async fn read_file(
+        &self,
+        request: Request<frontend::ReadFileRequest>,
+    ) -> Result<Response<frontend::ReadFileResponse>, Status> {
+        let request = request.into_inner();
+        let data = self
+            .files
+            .get(normalize(&request.file_path))
+            .ok_or_else(|| Status::not_found(format!("{}: no such file", request.file_path)))?;
+        let data = match request.range {
+            Some(range) => {
+                let start = (range.offset as usize).min(data.len());
+                let end = match range.length {
+                    0 => data.len(),
+                    length => (start + length as usize).min(data.len()),
+                };
+                &data[start..end]
+            }
+            None => &data[..],
+        };
+        Ok(Response::new(frontend::ReadFileResponse {
+            data: data.to_vec(),
+        }))
+    }
+
+    async fn read_dir(
+        &self,
+        request: Request<frontend::ReadDirRequest>,
+    ) -> Result<Response<frontend::ReadDirResponse>, Status> {
+        let request = request.into_inner();
+        let dir = normalize(&request.dir_path);
+        let mut entries = self
+            .entries()
+            .filter(|entry| {
+                let (parent, name) = split_parent(entry);
+                parent == dir
+                    && (request.include_pattern.is_empty()
+                        || matches_pattern(&request.include_pattern, name))
+            })
+            .filter_map(|entry| self.stat(entry))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Response::new(frontend::ReadDirResponse { entries }))
+    }
+
+    async fn stat_file(
+        &self,
+        request: Request<frontend::StatFileRequest>,
+    ) -> Result<Response<frontend::StatFileResponse>, Status> {
+        let path = request.into_inner().path;
+        match self.stat(&path) {
+            Some(stat) => Ok(Response::new(frontend::StatFileResponse {
+                stat: Some(stat),
+            })),
+            None => Err(Status::not_found(format!("{}: no such file", path))),
+        }
+    }
+
+    async fn ping(
+        &self,
+        _: Request<frontend::PingRequest>,
+    ) -> Result<Response<frontend::PongResponse>, Status> {
+        let capabilities = self
+            .capabilities
+            .as_ref()
+            .ok_or_else(|| Status::unimplemented("ping"))?;
+        let caps = capabilities
+            .iter()
+            .map(|id| ApiCap {
+                id: id.clone(),
+                enabled: true,
+                ..Default::default()
+            })
+            .collect();
+        // Which list a capability is in doesn't matter to the frontend.
+        Ok(Response::new(frontend::PongResponse {
+            frontend_api_caps: caps,
+            llb_caps: vec![],
+            workers: vec![],
+        }))
+    }
+
+    async fn r#return(
+        &self,
+        request: Request<frontend::ReturnRequest>,
+    ) -> Result<Response<frontend::ReturnResponse>, Status> {
+        if self.fail_return {
+            return Err(Status::unavailable("the build was cancelled"));
+        }
+        self.returned.lock().unwrap().push(request.into_inner());
+        Ok(Response::new(frontend::ReturnResponse {}))
+    }
+
+    async fn inputs(
+        &self,
+        _: Request<frontend::InputsRequest>,
+    ) -> Result<Response<frontend::InputsResponse>, Status> {
+        match &self.inputs {
+            Some(definitions) => Ok(Response::new(frontend::InputsResponse {
+                definitions: definitions.clone(),
+            })),
+            None => Err(Status::unimplemented("inputs")),
+        }
+    }
+}
+
+#[test]
+fn serves_a_filesystem_from_memory() {
+    let bridge = MockBridge::default()
+        .with_file("Dockerfile", "FROM alpine")
+        .with_file("docker/base.Dockerfile", "FROM debian")
+        .with_symlink("docker/current.Dockerfile", "base.Dockerfile");
+
+    assert_eq!(bridge.stat("./Dockerfile").unwrap().size, 11);
+    assert_eq!(bridge.stat("docker").unwrap().mode, MODE_DIR | 0o755);
+    assert_eq!(
+        bridge.stat("docker/current.Dockerfile").unwrap().linkname,
+        "base.Dockerfile"
+    );
+    assert!(bridge.stat("missing").is_none());
+    assert!(bridge.stat("dock").is_none());
+    assert!(matches_pattern("*.Dockerfile", "base.Dockerfile"));
+    assert!(!matches_pattern("*.Dockerfile", "Dockerfile"));
+    assert!(matches_pattern("Dockerfile", "Dockerfile"));
+}
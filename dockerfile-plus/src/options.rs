@@ -1,272 +1,267 @@
-use std::io::Cursor;
-use std::iter::empty;
-
-use anyhow::Result;
-use serde::de::value::{MapDeserializer, SeqDeserializer};
-use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
-use serde::forward_to_deserialize_any;
-
-pub fn from_env<T, I>(pairs: I) -> Result<T>
-where
-    T: DeserializeOwned,
-    I: IntoIterator<Item = (String, String)>,
-{
-    let owned_pairs = pairs.into_iter().collect::<Vec<_>>();
-    let pairs = {
-        owned_pairs.iter().filter_map(|(name, value)| {
-            if name.starts_with("BUILDKIT_FRONTEND_OPT_") {
-                Some(value)
-            } else {
-                None
-            }
-        })
-    };
-
-    let deserializer = EnvDeserializer {
-        vals: pairs.map(|value| extract_name_and_value(&value)),
-    };
-
-    Ok(T::deserialize(deserializer)?)
-}
-
-#[derive(Debug)]
-struct EnvDeserializer<P> {
-    vals: P,
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+#[cfg(test)]
+use buildkit_frontend::options::{from_env, to_env, to_frontend_opt};
+use serde::{Deserialize, Serialize};
+
+use crate::expand::Limits;
+use crate::lint::LintLevel;
+use crate::lock::LockMode;
+use crate::logging::LogLevel;
+
+/// Frontend options understood by dockerfile-plus: the standard options of the Dockerfile
+/// frontend, followed by the `dockerfile-plus.*` ones.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DockerfileOptions {
+    pub filename: Option<PathBuf>,
+    pub context: Option<String>,
+    #[serde(rename = "dockerfilekey")]
+    pub dockerfile_key: Option<String>,
+    #[serde(rename = "contextkey")]
+    pub context_key: Option<String>,
+    pub target: Option<String>,
+    pub platform: Option<Vec<String>>,
+    #[serde(rename = "requestid")]
+    pub request_id: Option<String>,
+    #[serde(rename = "build-arg:", default)]
+    pub build_args: HashMap<String, String>,
+    #[serde(rename = "label:", default)]
+    pub labels: HashMap<String, String>,
+    /// Named build contexts, as in `context:alpine=docker-image://alpine:3.18`.
+    #[serde(rename = "context:", default)]
+    pub contexts: HashMap<String, String>,
+    /// Stages to build without cache. An empty list disables the cache for every stage.
+    #[serde(rename = "no-cache")]
+    pub no_cache: Option<Vec<String>>,
+    /// Extra `/etc/hosts` entries, as `<host>=<ip>`.
+    #[serde(rename = "add-hosts")]
+    pub add_hosts: Option<Vec<String>>,
+    #[serde(rename = "cache-from")]
+    pub cache_from: Option<Vec<String>>,
+    #[serde(rename = "image-resolve-mode")]
+    pub image_resolve_mode: Option<String>,
+    #[serde(rename = "force-network-mode")]
+    pub force_network_mode: Option<String>,
+    pub hostname: Option<String>,
+    #[serde(rename = "shm-size")]
+    pub shm_size: Option<u64>,
+    #[serde(rename = "multi-platform", default)]
+    pub multi_platform: bool,
+
+    #[serde(rename = "dockerfile-plus.lock", default)]
+    pub lock: LockMode,
+    #[serde(rename = "dockerfile-plus.max-include-size")]
+    pub max_include_size: Option<u64>,
+    #[serde(rename = "dockerfile-plus.max-expanded-size")]
+    pub max_expanded_size: Option<u64>,
+    #[serde(rename = "dockerfile-plus.max-includes")]
+    pub max_includes: Option<usize>,
+    #[serde(rename = "dockerfile-plus.lint")]
+    pub lint: Option<LintLevel>,
+    #[serde(rename = "dockerfile-plus.expand-only", default)]
+    pub expand_only: bool,
+    #[serde(rename = "dockerfile-plus.provenance-labels", default)]
+    pub provenance_labels: bool,
+    #[serde(rename = "dockerfile-plus.targets")]
+    pub targets: Option<Vec<String>>,
+    #[serde(rename = "dockerfile-plus.debug")]
+    pub debug: Option<LogLevel>,
+    /// Seconds the Dockerfile frontend may take to build the expanded Dockerfile.
+    #[serde(rename = "dockerfile-plus.frontend-timeout")]
+    pub frontend_timeout: Option<u64>,
+
+    /// Options dockerfile-plus doesn't know about, kept so they can be forwarded.
+    #[serde(rename = "*", default)]
+    pub unknown: BTreeMap<String, String>,
 }
 
-#[derive(Debug)]
-enum EnvValue<'de> {
-    Flag,
-    Json(&'de str),
-    Text(&'de str),
-}
-
-#[derive(Debug)]
-struct EnvItem<'de>(&'de str);
-
-fn extract_name_and_value(mut raw_value: &str) -> (&str, EnvValue) {
-    if raw_value.starts_with("build-arg:") {
-        raw_value = raw_value.trim_start_matches("build-arg:");
-    }
-
-    let mut parts = raw_value.splitn(2, '=');
-    let name = parts.next().unwrap();
-
-    match parts.next() {
-        None => (name, EnvValue::Flag),
-        Some(text) if text.is_empty() => (name, EnvValue::Flag),
-        Some(text) if &text[0..1] == "[" || &text[0..1] == "{" => (name, EnvValue::Json(text)),
-        Some(text) => (name, EnvValue::Text(text)),
-    }
-}
-
-impl<'de> IntoDeserializer<'de, serde::de::value::Error> for EnvValue<'de> {
-    type Deserializer = Self;
-
-    fn into_deserializer(self) -> Self::Deserializer {
-        self
+impl DockerfileOptions {
+    pub fn limits(&self) -> Limits {
+        let defaults = Limits::default();
+        Limits {
+            max_include_size: self.max_include_size.unwrap_or(defaults.max_include_size),
+            max_expanded_size: self.max_expanded_size.unwrap_or(defaults.max_expanded_size),
+            max_includes: self.max_includes.unwrap_or(defaults.max_includes),
+        }
     }
 }
 
-impl<'de> IntoDeserializer<'de, serde::de::value::Error> for EnvItem<'de> {
-    type Deserializer = Self;
-
-    fn into_deserializer(self) -> Self::Deserializer {
-        self
-    }
+#[cfg(test)]
+fn frontend_opts(options: &[&str]) -> Vec<(String, String)> {
+    options
+        .iter()
+        .enumerate()
+        .map(|(index, option)| {
+            (
+                format!("BUILDKIT_FRONTEND_OPT_{}", index),
+                option.to_string(),
+            )
+        })
+        .chain(vec![("PATH".to_string(), "/bin".to_string())])
+        .collect()
 }
 
-impl<'de> EnvItem<'de> {
-    fn infer<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde::de::value::Error> {
-        match self.0 {
-            "true" => visitor.visit_bool(true),
-            "false" => visitor.visit_bool(false),
-
-            _ => visitor.visit_str(self.0),
-        }
-    }
-
-    fn json<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, serde::de::value::Error> {
-        use serde::de::Deserializer;
-        use serde::de::Error;
-
-        serde_json::Deserializer::from_reader(Cursor::new(self.0))
-            .deserialize_any(visitor)
-            .map_err(serde::de::value::Error::custom)
-    }
+#[test]
+fn parses_docker_buildx_options() {
+    let options: DockerfileOptions = from_env(frontend_opts(&[
+        "filename=docker/Dockerfile",
+        "target=release",
+        "platform=linux/amd64,linux/arm64",
+        "build-arg:VERSION=1.2",
+        "build-arg:EMPTY=",
+        "build-arg:dockerfile-plus.lock=verify",
+        "label:org.opencontainers.image.source=https://github.com/edrevo/dockerfile-plus",
+        "context:alpine=docker-image://alpine:3.18",
+        "no-cache=",
+        "add-hosts=db=10.0.0.2,cache=10.0.0.3",
+        "cache-from=registry.example.com/app:cache",
+        "image-resolve-mode=pull",
+        "shm-size=67108864",
+        "multi-platform=true",
+    ]))
+    .unwrap();
+
+    assert_eq!(options.filename, Some(PathBuf::from("docker/Dockerfile")));
+    assert_eq!(options.target.as_deref(), Some("release"));
+    assert_eq!(
+        options.platform,
+        Some(vec!["linux/amd64".to_string(), "linux/arm64".to_string()])
+    );
+    assert_eq!(options.build_args["VERSION"], "1.2");
+    assert_eq!(options.build_args["EMPTY"], "");
+    assert_eq!(
+        options.labels["org.opencontainers.image.source"],
+        "https://github.com/edrevo/dockerfile-plus"
+    );
+    assert_eq!(options.contexts["alpine"], "docker-image://alpine:3.18");
+    assert_eq!(options.context, None);
+    assert_eq!(options.no_cache, Some(vec![]));
+    assert_eq!(
+        options.add_hosts,
+        Some(vec![
+            "db=10.0.0.2".to_string(),
+            "cache=10.0.0.3".to_string()
+        ])
+    );
+    assert_eq!(
+        options.cache_from,
+        Some(vec!["registry.example.com/app:cache".to_string()])
+    );
+    assert_eq!(options.image_resolve_mode.as_deref(), Some("pull"));
+    assert_eq!(options.shm_size, Some(67108864));
+    assert!(options.multi_platform);
+
+    // dockerfile-plus options passed as build args apply, and stay build args.
+    assert_eq!(options.lock, LockMode::Verify);
+    assert_eq!(options.build_args["dockerfile-plus.lock"], "verify");
 }
 
-impl<'de, P> de::Deserializer<'de> for EnvDeserializer<P>
-where
-    P: Iterator<Item = (&'de str, EnvValue<'de>)>,
-{
-    type Error = serde::de::value::Error;
-
-    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_map(MapDeserializer::new(self.vals))
-    }
-
-    forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf option unit unit_struct newtype_struct seq tuple
-        tuple_struct map struct enum identifier ignored_any
-    }
+#[test]
+fn frontend_options_take_precedence_over_build_args() {
+    let options: DockerfileOptions = from_env(frontend_opts(&[
+        "build-arg:dockerfile-plus.lint=warn",
+        "dockerfile-plus.lint=error",
+        "context=input:source",
+    ]))
+    .unwrap();
+
+    assert_eq!(options.lint, Some(LintLevel::Error));
+    assert_eq!(options.context.as_deref(), Some("input:source"));
+    assert!(options.contexts.is_empty());
+    assert!(options.build_args.contains_key("dockerfile-plus.lint"));
 }
 
-// The approach is shamelessly borrowed from https://github.com/softprops/envy/blob/master/src/lib.rs#L113
-macro_rules! forward_parsed_values_env_value {
-    ($($ty:ident => $method:ident,)*) => {
-        $(
-            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
-                where V: de::Visitor<'de>
-            {
-                match self {
-                    EnvValue::Flag => self.deserialize_any(visitor),
-                    EnvValue::Json(_) => self.deserialize_any(visitor),
-                    EnvValue::Text(contents) => {
-                        match contents.parse::<$ty>() {
-                            Ok(val) => val.into_deserializer().$method(visitor),
-                            Err(e) => Err(de::Error::custom(format_args!("{} while parsing value '{}'", e, contents)))
-                        }
-                    }
-                }
-            }
-        )*
-    }
+#[test]
+fn parses_values_by_target_type() {
+    let options: DockerfileOptions = from_env(frontend_opts(&[
+        "build-arg:JAVA_OPTS=-Xmx1g,-Xms512m",
+        "build-arg:JSON=[1,2]",
+        "target=[release]",
+        r#"dockerfile-plus.targets="api,v2",worker,"say ""hi""""#,
+        "cache-from=[\"registry.example.com/app:cache\"]",
+    ]))
+    .unwrap();
+
+    assert_eq!(options.build_args["JAVA_OPTS"], "-Xmx1g,-Xms512m");
+    assert_eq!(options.build_args["JSON"], "[1,2]");
+    assert_eq!(options.target.as_deref(), Some("[release]"));
+    assert_eq!(
+        options.targets,
+        Some(vec![
+            "api,v2".to_string(),
+            "worker".to_string(),
+            r#"say "hi""#.to_string()
+        ])
+    );
+    assert_eq!(
+        options.cache_from,
+        Some(vec!["registry.example.com/app:cache".to_string()])
+    );
 }
 
-macro_rules! forward_parsed_values_env_item {
-    ($($ty:ident => $method:ident,)*) => {
-        $(
-            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
-                where V: de::Visitor<'de>
-            {
-                match self.0.parse::<$ty>() {
-                    Ok(val) => val.into_deserializer().$method(visitor),
-                    Err(e) => Err(de::Error::custom(format_args!("{} while parsing value '{}'", e, self.0)))
-                }
-            }
-        )*
-    }
+#[test]
+fn errors_name_the_option() {
+    let error =
+        from_env::<DockerfileOptions, _>(frontend_opts(&["dockerfile-plus.max-includes=many"]))
+            .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("option 'dockerfile-plus.max-includes'"));
+
+    let error = from_env::<DockerfileOptions, _>(frontend_opts(&["dockerfile-plus.targets=\"api"]))
+        .unwrap_err();
+    assert!(error.to_string().contains("unterminated quote"));
 }
 
-impl<'de> de::Deserializer<'de> for EnvValue<'de> {
-    type Error = serde::de::value::Error;
-
-    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        match self {
-            EnvValue::Flag => visitor.visit_bool(true),
-            EnvValue::Json(contents) => EnvItem(contents).json(visitor),
-            EnvValue::Text(contents) => {
-                if !contents.contains(',') {
-                    EnvItem(contents).infer(visitor)
-                } else {
-                    SeqDeserializer::new(contents.split(',')).deserialize_seq(visitor)
-                }
-            }
-        }
-    }
-
-    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        match self {
-            EnvValue::Flag => SeqDeserializer::new(empty::<&'de str>()).deserialize_seq(visitor),
-            EnvValue::Json(contents) => EnvItem(contents).json(visitor),
-            EnvValue::Text(contents) => {
-                SeqDeserializer::new(contents.split(',')).deserialize_seq(visitor)
-            }
-        }
-    }
-
-    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_some(self)
-    }
-
-    forward_parsed_values_env_value! {
-        bool => deserialize_bool,
-        u8 => deserialize_u8,
-        u16 => deserialize_u16,
-        u32 => deserialize_u32,
-        u64 => deserialize_u64,
-        u128 => deserialize_u128,
-        i8 => deserialize_i8,
-        i16 => deserialize_i16,
-        i32 => deserialize_i32,
-        i64 => deserialize_i64,
-        i128 => deserialize_i128,
-        f32 => deserialize_f32,
-        f64 => deserialize_f64,
-    }
-
-    forward_to_deserialize_any! {
-        byte_buf
-        bytes
-        char
-        enum
-        identifier
-        ignored_any
-        map
-        newtype_struct
-        str
-        string
-        struct
-        tuple
-        tuple_struct
-        unit
-        unit_struct
-    }
+#[test]
+fn forwards_unknown_options() {
+    let env = frontend_opts(&[
+        "target=release",
+        "attest:sbom=generator=image",
+        "source-date-epoch=0",
+        "build-arg:VERSION=1.2",
+    ]);
+    let options: DockerfileOptions = from_env(env).unwrap();
+    assert_eq!(options.unknown["attest:sbom"], "generator=image");
+    assert_eq!(options.unknown["source-date-epoch"], "0");
+
+    let frontend_opt = to_frontend_opt(&options).unwrap();
+    assert_eq!(frontend_opt["target"], "release");
+    assert_eq!(frontend_opt["attest:sbom"], "generator=image");
+    assert_eq!(frontend_opt["build-arg:VERSION"], "1.2");
+    assert!(!frontend_opt.contains_key("filename"));
 }
 
-impl<'de> de::Deserializer<'de> for EnvItem<'de> {
-    type Error = serde::de::value::Error;
-
-    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        self.0.into_deserializer().deserialize_any(visitor)
-    }
-
-    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        self.json(visitor)
-    }
-
-    fn deserialize_struct<V: Visitor<'de>>(
-        self,
-        _: &'static str,
-        _: &'static [&'static str],
-        visitor: V,
-    ) -> Result<V::Value, Self::Error> {
-        self.json(visitor)
-    }
-
-    forward_parsed_values_env_item! {
-        bool => deserialize_bool,
-        u8 => deserialize_u8,
-        u16 => deserialize_u16,
-        u32 => deserialize_u32,
-        u64 => deserialize_u64,
-        u128 => deserialize_u128,
-        i8 => deserialize_i8,
-        i16 => deserialize_i16,
-        i32 => deserialize_i32,
-        i64 => deserialize_i64,
-        i128 => deserialize_i128,
-        f32 => deserialize_f32,
-        f64 => deserialize_f64,
-    }
-
-    forward_to_deserialize_any! {
-        byte_buf
-        bytes
-        char
-        enum
-        identifier
-        ignored_any
-        newtype_struct
-        option
-        seq
-        str
-        string
-        tuple
-        tuple_struct
-        unit
-        unit_struct
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn options_round_trip(
+        target in proptest::option::of("[ -~]*"),
+        platform in proptest::option::of(proptest::collection::vec("[ -~]*", 0..4)),
+        build_args in proptest::collection::hash_map("[A-Z_][A-Z0-9_]*", "[ -~]*", 0..4),
+        labels in proptest::collection::hash_map("[a-z][a-z.]*", "[ -~]*", 0..4),
+        unknown in proptest::collection::btree_map("x-[a-z-]*", "[ -~]*", 0..4),
+        no_cache in proptest::option::of(proptest::collection::vec("[a-z,\"\\[]*", 0..4)),
+        shm_size in proptest::option::of(proptest::num::u64::ANY),
+        multi_platform in proptest::bool::ANY,
+        lint in proptest::option::of(proptest::sample::select(vec![LintLevel::Error, LintLevel::Warn])),
+    ) {
+        let base: DockerfileOptions = from_env(vec![]).unwrap();
+        let options = DockerfileOptions {
+            target,
+            platform,
+            build_args,
+            labels,
+            unknown,
+            no_cache,
+            shm_size,
+            multi_platform,
+            lint,
+            ..base
+        };
+
+        let parsed: DockerfileOptions = from_env(to_env(&options).unwrap()).unwrap();
+        proptest::prop_assert_eq!(parsed, options);
     }
 }
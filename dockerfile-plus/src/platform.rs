@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{bail, Context, Result};
+use buildkit_proto::{
+    google::rpc::Status,
+    moby::buildkit::v1::frontend::{self, result::Result as RefResult, Ref, RefMap, ReturnRequest},
+};
+use serde::{Deserialize, Serialize};
+
+/// Metadata key listing the platforms of a multi-platform result.
+const PLATFORMS_METADATA_KEY: &str = "refs.platforms";
+
+/// A target platform, as in `linux/arm64/v8`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    pub fn parse(spec: &str) -> Result<Platform> {
+        if spec == "local" {
+            return Ok(Platform::default());
+        }
+
+        let parts = spec.split('/').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [os, architecture] if !os.is_empty() && !architecture.is_empty() => Ok(Platform {
+                os: os.to_string(),
+                architecture: architecture.to_string(),
+                variant: None,
+            }),
+            [os, architecture, variant]
+                if !os.is_empty() && !architecture.is_empty() && !variant.is_empty() =>
+            {
+                Ok(Platform {
+                    os: os.to_string(),
+                    architecture: architecture.to_string(),
+                    variant: Some(variant.to_string()),
+                })
+            }
+            _ => bail!(
+                "Invalid platform \"{}\": expected <os>/<architecture>[/<variant>]",
+                spec
+            ),
+        }
+    }
+
+    /// Whether this platform is the one described by `spec`. A spec without a variant matches
+    /// every variant of its architecture.
+    pub fn matches(&self, spec: &str) -> Result<bool> {
+        let other = Platform::parse(spec)?;
+        Ok(self.os == other.os
+            && self.architecture == other.architecture
+            && (other.variant.is_none() || self.variant == other.variant))
+    }
+
+    /// Replaces the `${TARGETPLATFORM}`, `${TARGETOS}`, `${TARGETARCH}` and `${TARGETVARIANT}`
+    /// build args, which BuildKit defines for every stage, in `text`.
+    pub fn substitute(&self, text: &str) -> String {
+        text.replace("${TARGETPLATFORM}", &self.to_string())
+            .replace("${TARGETOS}", &self.os)
+            .replace("${TARGETARCH}", &self.architecture)
+            .replace("${TARGETVARIANT}", self.variant.as_deref().unwrap_or(""))
+    }
+}
+
+/// The platform this frontend runs on, which is the one BuildKit builds for by default.
+impl Default for Platform {
+    fn default() -> Platform {
+        let os = match std::env::consts::OS {
+            "macos" => "darwin",
+            os => os,
+        };
+        let (architecture, variant) = match std::env::consts::ARCH {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", None),
+            "arm" => ("arm", Some("v7")),
+            "powerpc64" => ("ppc64le", None),
+            architecture => (architecture, None),
+        };
+        Platform {
+            os: os.to_string(),
+            architecture: architecture.to_string(),
+            variant: variant.map(str::to_string),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Platforms {
+    platforms: Vec<PlatformEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PlatformEntry {
+    id: String,
+    platform: Platform,
+}
+
+/// Combines the results of building each platform separately into a single multi-platform
+/// result, as the Dockerfile frontend returns when building several platforms at once.
+pub fn combine(results: Vec<(Platform, ReturnRequest)>) -> Result<ReturnRequest> {
+    let mut refs = HashMap::new();
+    let mut metadata = HashMap::new();
+    let mut platforms = vec![];
+
+    for (platform, request) in &results {
+        let id = platform.to_string();
+        if let Some(error) = &request.error {
+            return Ok(ReturnRequest {
+                result: None,
+                error: Some(Status {
+                    message: format!("platform {}: {}", id, error.message),
+                    ..error.clone()
+                }),
+            });
+        }
+
+        let result = match &request.result {
+            Some(result) => result,
+            None => bail!("Platform \"{}\" returned no result", id),
+        };
+        let platform_ref = match &result.result {
+            Some(RefResult::RefDeprecated(ref_id)) => Ref {
+                id: ref_id.clone(),
+                def: None,
+            },
+            Some(RefResult::Ref(platform_ref)) => platform_ref.clone(),
+            // A build for a single platform may still be returned as a map with one entry.
+            Some(RefResult::Refs(map)) if map.refs.len() == 1 => {
+                map.refs.values().next().unwrap().clone()
+            }
+            Some(RefResult::RefsDeprecated(map)) if map.refs.len() == 1 => Ref {
+                id: map.refs.values().next().unwrap().clone(),
+                def: None,
+            },
+            _ => bail!("Platform \"{}\" returned more than one result", id),
+        };
+
+        for (key, value) in &result.metadata {
+            // Keys of single entry maps are already suffixed with the platform.
+            let key = key.strip_suffix(&format!("/{}", id)).unwrap_or(key);
+            if key != PLATFORMS_METADATA_KEY {
+                metadata.insert(format!("{}/{}", key, id), value.clone());
+            }
+        }
+        refs.insert(id.clone(), platform_ref);
+        platforms.push(PlatformEntry {
+            id,
+            platform: platform.clone(),
+        });
+    }
+
+    metadata.insert(
+        PLATFORMS_METADATA_KEY.to_string(),
+        serde_json::to_vec(&Platforms { platforms })?,
+    );
+
+    Ok(ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::Refs(RefMap { refs })),
+            metadata,
+        }),
+        error: None,
+    })
+}
+
+/// Adds `entry` to a build result, as the result `id` for `platform`, so that exporters export it
+/// next to the build. A build of a single platform, `build_platform`, becomes a multi-platform
+/// result, as exporters only export several results when they are platforms. Results keyed by
+/// target only get the entry under its own key.
+pub fn add_entry(
+    request: ReturnRequest,
+    build_platform: &Platform,
+    id: &str,
+    platform: &Platform,
+    entry: Ref,
+) -> Result<ReturnRequest> {
+    let mut result = match request {
+        ReturnRequest {
+            result: Some(result),
+            error: None,
+        } => result,
+        failed => return Ok(failed),
+    };
+
+    let build_id = build_platform.to_string();
+    let single = |build_ref| vec![(build_id.clone(), build_ref)].into_iter().collect();
+    let mut refs: HashMap<String, Ref> = match result.result {
+        Some(RefResult::RefDeprecated(ref_id)) => single(Ref {
+            id: ref_id,
+            def: None,
+        }),
+        Some(RefResult::Ref(build_ref)) => single(build_ref),
+        Some(RefResult::Refs(map)) => map.refs,
+        Some(RefResult::RefsDeprecated(map)) => map
+            .refs
+            .into_iter()
+            .map(|(key, id)| (key, Ref { id, def: None }))
+            .collect(),
+        None => bail!("The build returned no result"),
+    };
+
+    let entries = match result.metadata.get(PLATFORMS_METADATA_KEY) {
+        Some(platforms) => Some(
+            serde_json::from_slice::<Platforms>(platforms)
+                .with_context(|| format!("Could not parse \"{}\"", PLATFORMS_METADATA_KEY))?
+                .platforms,
+        ),
+        None if refs.len() == 1 && refs.contains_key(&build_id) => {
+            // Metadata of a single result becomes that of its platform.
+            result.metadata = result
+                .metadata
+                .into_iter()
+                .map(|(key, value)| (format!("{}/{}", key, build_id), value))
+                .collect();
+            Some(vec![PlatformEntry {
+                id: build_id,
+                platform: build_platform.clone(),
+            }])
+        }
+        // Results keyed by something else than platforms, such as targets, are left as is.
+        None => None,
+    };
+
+    refs.insert(id.to_string(), entry);
+    if let Some(mut entries) = entries {
+        entries.push(PlatformEntry {
+            id: id.to_string(),
+            platform: platform.clone(),
+        });
+        result.metadata.insert(
+            PLATFORMS_METADATA_KEY.to_string(),
+            serde_json::to_vec(&Platforms { platforms: entries })?,
+        );
+    }
+
+    Ok(ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::Refs(RefMap { refs })),
+            metadata: result.metadata,
+        }),
+        error: None,
+    })
+}
+
+#[test]
+fn parses_and_substitutes_platforms() {
+    let platform = Platform::parse("linux/arm/v7").unwrap();
+    assert_eq!(platform.to_string(), "linux/arm/v7");
+    assert!(platform.matches("linux/arm").unwrap());
+    assert!(!platform.matches("linux/arm/v6").unwrap());
+    assert!(!platform.matches("linux/arm64").unwrap());
+    assert!(Platform::parse("linux").is_err());
+
+    assert_eq!(
+        platform.substitute("INCLUDE+ ${TARGETOS}/${TARGETARCH}${TARGETVARIANT}.Dockerfile"),
+        "INCLUDE+ linux/armv7.Dockerfile"
+    );
+}
+
+#[test]
+fn combines_results_by_platform() {
+    let result = |id: &str| ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::RefDeprecated(id.to_string())),
+            metadata: vec![("containerimage.config".to_string(), id.as_bytes().to_vec())]
+                .into_iter()
+                .collect(),
+        }),
+        error: None,
+    };
+
+    let combined = combine(vec![
+        (Platform::parse("linux/amd64").unwrap(), result("ref-amd64")),
+        (Platform::parse("linux/arm64").unwrap(), result("ref-arm64")),
+    ])
+    .unwrap()
+    .result
+    .unwrap();
+
+    match combined.result {
+        Some(RefResult::Refs(map)) => {
+            assert_eq!(map.refs["linux/amd64"].id, "ref-amd64");
+            assert_eq!(map.refs["linux/arm64"].id, "ref-arm64");
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert_eq!(
+        combined.metadata["containerimage.config/linux/arm64"],
+        b"ref-arm64"
+    );
+    assert_eq!(
+        combined.metadata[PLATFORMS_METADATA_KEY],
+        br#"{"platforms":[{"id":"linux/amd64","platform":{"os":"linux","architecture":"amd64"}},{"id":"linux/arm64","platform":{"os":"linux","architecture":"arm64"}}]}"#.to_vec()
+    );
+}
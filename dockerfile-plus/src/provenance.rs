@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use buildkit_proto::moby::buildkit::v1::frontend::ReturnRequest;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::expand::IncludeRecord;
+
+/// Key of the provenance JSON in the result metadata.
+pub const PROVENANCE_METADATA_KEY: &str = "dockerfile-plus.provenance";
+
+/// Image label holding the provenance JSON, when labels are enabled.
+pub const PROVENANCE_LABEL: &str = "dev.dockerfile-plus.provenance";
+
+/// Prefix of the metadata keys holding image configs, one per platform for multi-platform builds.
+const IMAGE_CONFIG_METADATA_PREFIX: &str = "containerimage.config";
+
+#[derive(Debug, Serialize)]
+struct Provenance<'a> {
+    includes: &'a [IncludeRecord],
+}
+
+/// Records every included fragment in the metadata of a successful result and, optionally,
+/// as a label of every image config in it.
+pub fn attach(request: &mut ReturnRequest, includes: &[IncludeRecord], labels: bool) -> Result<()> {
+    let result = match request.result.as_mut() {
+        Some(result) => result,
+        None => return Ok(()),
+    };
+
+    let provenance = serde_json::to_string(&Provenance { includes })?;
+
+    if labels {
+        for (key, config) in result
+            .metadata
+            .iter_mut()
+            .filter(|(key, _)| key.starts_with(IMAGE_CONFIG_METADATA_PREFIX))
+        {
+            let mut image_config: Value = serde_json::from_slice(config)
+                .with_context(|| format!("Could not parse the image config in \"{}\"", key))?;
+            image_config["config"]["Labels"][PROVENANCE_LABEL] = Value::String(provenance.clone());
+            *config = serde_json::to_vec(&image_config)?;
+        }
+    }
+
+    result
+        .metadata
+        .insert(PROVENANCE_METADATA_KEY.to_string(), provenance.into_bytes());
+    Ok(())
+}
+
+#[test]
+fn labels_image_configs() {
+    use buildkit_proto::moby::buildkit::v1::frontend::{self, result::Result as RefResult};
+
+    let includes = vec![IncludeRecord {
+        file: "Dockerfile".into(),
+        line: 3,
+        include: "Dockerfile.common".into(),
+        source: "context",
+        reference: None,
+        digest: "sha256:0000".into(),
+    }];
+    let mut request = ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::RefDeprecated("ref".into())),
+            metadata: vec![(
+                "containerimage.config".to_string(),
+                br#"{"config":{"Labels":null}}"#.to_vec(),
+            )]
+            .into_iter()
+            .collect(),
+        }),
+        error: None,
+    };
+
+    attach(&mut request, &includes, true).unwrap();
+
+    let metadata = request.result.unwrap().metadata;
+    let provenance = r#"{"includes":[{"file":"Dockerfile","line":3,"include":"Dockerfile.common","source":"context","reference":null,"digest":"sha256:0000"}]}"#;
+    assert_eq!(metadata[PROVENANCE_METADATA_KEY], provenance.as_bytes());
+
+    let image_config: Value = serde_json::from_slice(&metadata["containerimage.config"]).unwrap();
+    assert_eq!(
+        image_config["config"]["Labels"][PROVENANCE_LABEL],
+        provenance
+    );
+}
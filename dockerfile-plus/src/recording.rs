@@ -0,0 +1,578 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use buildkit_proto::{
+    google::rpc,
+    moby::buildkit::v1::frontend::{
+        self, llb_bridge_client::LlbBridgeClient, llb_bridge_server::LlbBridge,
+    },
+};
+use log::warn;
+use prost::Message;
+use tonic::{transport::Channel, Code, Request, Response, Status};
+
+use buildkit_frontend::{
+    options::FRONTEND_OPT_PREFIX, stdio::connect_in_process, transport::GATEWAY_ENV, Bridge,
+    Frontend, Transport,
+};
+
+use crate::DockerfilePlus;
+
+/// Environment variable naming the file to record the gateway traffic of a build into.
+pub const RECORD_ENV: &str = "DOCKERFILE_PLUS_RECORD";
+
+/// A recorded build: the frontend options it was run with, and every gateway request it sent
+/// to BuildKit, in order. Everything is recorded as is, build args and secrets included, since
+/// the requests a build sends depend on them.
+///
+/// Concatenated protobuf messages merge into one, with their repeated fields appended, so
+/// exchanges are appended to the file as they happen and a partial recording stays readable.
+#[derive(Clone, PartialEq, Message)]
+pub struct Recording {
+    /// Frontend options, as `key=value`.
+    #[prost(string, repeated, tag = "1")]
+    pub options: Vec<String>,
+    #[prost(message, repeated, tag = "2")]
+    pub exchanges: Vec<Exchange>,
+}
+
+/// A gateway request and its response, or the status it failed with.
+#[derive(Clone, PartialEq, Message)]
+pub struct Exchange {
+    /// Name of the `LLBBridge` method, as in `ReadFile`.
+    #[prost(string, tag = "1")]
+    pub method: String,
+    #[prost(bytes, tag = "2")]
+    pub request: Vec<u8>,
+    #[prost(bytes, tag = "3")]
+    pub response: Vec<u8>,
+    #[prost(message, optional, tag = "4")]
+    pub error: Option<rpc::Status>,
+}
+
+impl Recording {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Recording> {
+        let bytes = std::fs::read(&path)
+            .with_context(|| format!("Could not read {}", path.as_ref().display()))?;
+        Recording::decode(&bytes[..])
+            .with_context(|| format!("{} is not a recording", path.as_ref().display()))
+    }
+
+    /// The result the recorded build returned to BuildKit, if it got that far.
+    pub fn returned(&self) -> Result<Option<frontend::ReturnRequest>> {
+        match self.exchanges.iter().rev().find(|e| e.method == "Return") {
+            Some(exchange) => Ok(Some(frontend::ReturnRequest::decode(
+                &exchange.request[..],
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut bytes = vec![];
+    message
+        .encode(&mut bytes)
+        .expect("encoding into a Vec can't fail");
+    bytes
+}
+
+/// Wraps `client` to record its traffic, if `DOCKERFILE_PLUS_RECORD` is set.
+pub async fn record_if_requested(
+    client: LlbBridgeClient<Channel>,
+) -> Result<LlbBridgeClient<Channel>> {
+    match std::env::var_os(RECORD_ENV) {
+        Some(path) => {
+            let options = std::env::vars()
+                .filter(|(name, _)| name.starts_with(FRONTEND_OPT_PREFIX))
+                .map(|(_, value)| value)
+                .collect();
+            connect_in_process(RecordingBridge::create(client, path, options)?).await
+        }
+        None => Ok(client),
+    }
+}
+
+/// Forwards every request to BuildKit, and records it along with its response.
+pub struct RecordingBridge {
+    client: LlbBridgeClient<Channel>,
+    file: Mutex<File>,
+}
+
+impl RecordingBridge {
+    pub fn create<P: AsRef<Path>>(
+        client: LlbBridgeClient<Channel>,
+        path: P,
+        options: Vec<String>,
+    ) -> Result<RecordingBridge> {
+        let mut file = File::create(&path)
+            .with_context(|| format!("Could not create {}", path.as_ref().display()))?;
+        file.write_all(&encode(&Recording {
+            options,
+            exchanges: vec![],
+        }))?;
+        Ok(RecordingBridge {
+            client,
+            file: Mutex::new(file),
+        })
+    }
+
+    async fn forward<Req, Res, F, Fut>(
+        &self,
+        method: &str,
+        request: Request<Req>,
+        send: F,
+    ) -> Result<Response<Res>, Status>
+    where
+        Req: Message + Clone,
+        Res: Message,
+        F: FnOnce(LlbBridgeClient<Channel>, Req) -> Fut,
+        Fut: Future<Output = Result<Response<Res>, Status>>,
+    {
+        let request = request.into_inner();
+        let result = send(self.client.clone(), request.clone()).await;
+
+        let mut exchange = Exchange {
+            method: method.to_string(),
+            request: encode(&request),
+            ..Default::default()
+        };
+        match &result {
+            Ok(response) => exchange.response = encode(response.get_ref()),
+            Err(status) => {
+                exchange.error = Some(rpc::Status {
+                    code: status.code() as i32,
+                    message: status.message().to_string(),
+                    details: vec![],
+                })
+            }
+        }
+        let recording = encode(&Recording {
+            options: vec![],
+            exchanges: vec![exchange],
+        });
+        // A recording with missing requests is better than a failed build.
+        if let Err(e) = self.file.lock().unwrap().write_all(&recording) {
+            warn!("Could not record {}: {}", method, e);
+        }
+
+        result
+    }
+}
+
+#[tonic::async_trait]
+impl LlbBridge for RecordingBridge {
+    async fn resolve_image_config(
+        &self,
+        request: Request<frontend::ResolveImageConfigRequest>,
+    ) -> Result<Response<frontend::ResolveImageConfigResponse>, Status> {
+        self.forward(
+            "ResolveImageConfig",
+            request,
+            |mut client, request| async move { client.resolve_image_config(request).await },
+        )
+        .await
+    }
+
+    async fn solve(
+        &self,
+        request: Request<frontend::SolveRequest>,
+    ) -> Result<Response<frontend::SolveResponse>, Status> {
+        self.forward("Solve", request, |mut client, request| async move {
+            client.solve(request).await
+        })
+        .await
+    }
+
+    async fn read_file(
+        &self,
+        request: Request<frontend::ReadFileRequest>,
+    ) -> Result<Response<frontend::ReadFileResponse>, Status> {
+        self.forward("ReadFile", request, |mut client, request| async move {
+            client.read_file(request).await
+        })
+        .await
+    }
+
+    async fn read_dir(
+        &self,
+        request: Request<frontend::ReadDirRequest>,
+    ) -> Result<Response<frontend::ReadDirResponse>, Status> {
+        self.forward("ReadDir", request, |mut client, request| async move {
+            client.read_dir(request).await
+        })
+        .await
+    }
+
+    async fn stat_file(
+        &self,
+        request: Request<frontend::StatFileRequest>,
+    ) -> Result<Response<frontend::StatFileResponse>, Status> {
+        self.forward("StatFile", request, |mut client, request| async move {
+            client.stat_file(request).await
+        })
+        .await
+    }
+
+    async fn ping(
+        &self,
+        request: Request<frontend::PingRequest>,
+    ) -> Result<Response<frontend::PongResponse>, Status> {
+        self.forward("Ping", request, |mut client, request| async move {
+            client.ping(request).await
+        })
+        .await
+    }
+
+    async fn r#return(
+        &self,
+        request: Request<frontend::ReturnRequest>,
+    ) -> Result<Response<frontend::ReturnResponse>, Status> {
+        self.forward("Return", request, |mut client, request| async move {
+            client.r#return(request).await
+        })
+        .await
+    }
+
+    async fn inputs(
+        &self,
+        request: Request<frontend::InputsRequest>,
+    ) -> Result<Response<frontend::InputsResponse>, Status> {
+        self.forward("Inputs", request, |mut client, request| async move {
+            client.inputs(request).await
+        })
+        .await
+    }
+}
+
+/// Answers requests with the responses of a recording, instead of talking to BuildKit.
+pub struct ReplayBridge {
+    exchanges: Vec<Exchange>,
+    /// The requests passed to `Return`, which are captured instead of replayed.
+    pub returned: Arc<Mutex<Vec<frontend::ReturnRequest>>>,
+}
+
+impl ReplayBridge {
+    pub fn new(recording: Recording) -> ReplayBridge {
+        ReplayBridge {
+            exchanges: recording.exchanges,
+            returned: Default::default(),
+        }
+    }
+
+    /// Answers with the response to the first recorded request equal to `request`. Requests
+    /// are compared once decoded, since maps aren't encoded in a stable order.
+    #[allow(clippy::result_large_err)] // The error is what `LlbBridge` methods return.
+    fn answer<Req, Res>(&self, method: &str, request: Request<Req>) -> Result<Response<Res>, Status>
+    where
+        Req: Message + Default + PartialEq,
+        Res: Message + Default,
+    {
+        let request = request.into_inner();
+        let exchange = self
+            .exchanges
+            .iter()
+            .filter(|exchange| exchange.method == method)
+            .find(|exchange| {
+                matches!(Req::decode(&exchange.request[..]), Ok(recorded) if recorded == request)
+            })
+            .ok_or_else(|| {
+                Status::failed_precondition(format!(
+                    "The recording has no {} request matching {:?}",
+                    method, request
+                ))
+            })?;
+
+        match &exchange.error {
+            Some(status) => Err(Status::new(
+                Code::from_i32(status.code),
+                status.message.clone(),
+            )),
+            None => Res::decode(&exchange.response[..])
+                .map(Response::new)
+                .map_err(|e| Status::data_loss(format!("Invalid recorded response: {}", e))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl LlbBridge for ReplayBridge {
+    async fn resolve_image_config(
+        &self,
+        request: Request<frontend::ResolveImageConfigRequest>,
+    ) -> Result<Response<frontend::ResolveImageConfigResponse>, Status> {
+        self.answer("ResolveImageConfig", request)
+    }
+
+    async fn solve(
+        &self,
+        request: Request<frontend::SolveRequest>,
+    ) -> Result<Response<frontend::SolveResponse>, Status> {
+        self.answer("Solve", request)
+    }
+
+    async fn read_file(
+        &self,
+        request: Request<frontend::ReadFileRequest>,
+    ) -> Result<Response<frontend::ReadFileResponse>, Status> {
+        self.answer("ReadFile", request)
+    }
+
+    async fn read_dir(
+        &self,
+        request: Request<frontend::ReadDirRequest>,
+    ) -> Result<Response<frontend::ReadDirResponse>, Status> {
+        self.answer("ReadDir", request)
+    }
+
+    async fn stat_file(
+        &self,
+        request: Request<frontend::StatFileRequest>,
+    ) -> Result<Response<frontend::StatFileResponse>, Status> {
+        self.answer("StatFile", request)
+    }
+
+    async fn ping(
+        &self,
+        request: Request<frontend::PingRequest>,
+    ) -> Result<Response<frontend::PongResponse>, Status> {
+        // Recordings made before the frontend negotiated capabilities don't have a ping to
+        // answer with, and replay as if BuildKit didn't answer pings.
+        if self
+            .exchanges
+            .iter()
+            .all(|exchange| exchange.method != "Ping")
+        {
+            return Err(Status::unimplemented("The recording has no Ping request"));
+        }
+        self.answer("Ping", request)
+    }
+
+    async fn r#return(
+        &self,
+        request: Request<frontend::ReturnRequest>,
+    ) -> Result<Response<frontend::ReturnResponse>, Status> {
+        self.returned.lock().unwrap().push(request.into_inner());
+        Ok(Response::new(frontend::ReturnResponse {}))
+    }
+
+    async fn inputs(
+        &self,
+        request: Request<frontend::InputsRequest>,
+    ) -> Result<Response<frontend::InputsResponse>, Status> {
+        self.answer("Inputs", request)
+    }
+}
+
+/// Runs `dockerfile-plus replay [--serve <address>] <recording>`, which reruns a recorded build
+/// offline against the recorded responses, and returns the exit code. With `--serve`, the
+/// recorded responses are served at the address instead, for a frontend to connect to.
+pub async fn run_cli(args: &[String]) -> i32 {
+    let (address, path) = match args {
+        [path] => (None, path),
+        [flag, address, path] if flag == "--serve" => (Some(address), path),
+        _ => {
+            eprintln!("Usage: dockerfile-plus replay [--serve <address>] <recording>");
+            return 2;
+        }
+    };
+    let result = match address {
+        Some(address) => serve(path, address).await.map(|()| true),
+        None => replay(&DockerfilePlus, path).await,
+    };
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}: {:#}", path, e);
+            1
+        }
+    }
+}
+
+/// Serves a recording at `address`, and prints how to run a frontend against it.
+async fn serve(path: &str, address: &str) -> Result<()> {
+    let transport: Transport = address.parse()?;
+    let recording = Recording::read(path)?;
+    eprintln!(
+        "Serving the recording on {}. Run the frontend with:",
+        transport
+    );
+    eprintln!("  {}={}", GATEWAY_ENV, transport);
+    for (index, option) in recording.options.iter().enumerate() {
+        eprintln!("  {}{}={}", FRONTEND_OPT_PREFIX, index, option);
+    }
+    transport.serve(ReplayBridge::new(recording)).await
+}
+
+/// Replays a recording with `frontend`, and returns whether the replayed build succeeded.
+async fn replay<F: Frontend>(frontend: &F, path: &str) -> Result<bool> {
+    let recording = Recording::read(path)?;
+    let recorded = recording.returned()?;
+
+    // The options are read from the environment, like BuildKit passes them.
+    for (name, _) in std::env::vars() {
+        if name.starts_with(FRONTEND_OPT_PREFIX) {
+            std::env::remove_var(name);
+        }
+    }
+    for (index, option) in recording.options.iter().enumerate() {
+        std::env::set_var(format!("{}{}", FRONTEND_OPT_PREFIX, index), option);
+    }
+
+    let bridge = ReplayBridge::new(recording);
+    let returned = bridge.returned.clone();
+    buildkit_frontend::run_with(frontend, Bridge::new(connect_in_process(bridge).await?)).await?;
+    let replayed = returned
+        .lock()
+        .unwrap()
+        .pop()
+        .context("The replayed build returned no result")?;
+
+    match &replayed.error {
+        Some(status) => println!("The replayed build failed: {}", status.message),
+        None => println!("The replayed build succeeded"),
+    }
+    if recorded.as_ref() != Some(&replayed) {
+        println!("The recorded build returned a different result");
+    }
+    Ok(replayed.error.is_none())
+}
+
+#[tokio::test]
+async fn replays_recorded_traffic() {
+    use crate::mock_bridge::MockBridge;
+
+    let path = std::env::temp_dir().join(format!("dockerfile-plus-{}.rec", std::process::id()));
+    let upstream = MockBridge {
+        files: vec![("Dockerfile".to_string(), b"FROM alpine".to_vec())]
+            .into_iter()
+            .collect(),
+        ..Default::default()
+    }
+    .connect()
+    .await;
+    let bridge = RecordingBridge::create(upstream, &path, vec!["target=release".to_string()]);
+    let mut client = Bridge::new(connect_in_process(bridge.unwrap()).await.unwrap());
+    async fn read(bridge: &mut Bridge) -> (Vec<u8>, anyhow::Error) {
+        let layer = buildkit_frontend::Reference::new("layer");
+        let found = bridge.read_file(&layer, "Dockerfile", None).await;
+        let missing = bridge.read_file(&layer, "missing", None).await;
+        (found.unwrap(), missing.unwrap_err())
+    }
+    read(&mut client).await;
+    client
+        .return_result(frontend::ReturnRequest::default())
+        .await
+        .unwrap();
+
+    let recording = Recording::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(recording.options, vec!["target=release"]);
+    assert_eq!(recording.exchanges.len(), 3);
+    assert_eq!(
+        recording.returned().unwrap(),
+        Some(frontend::ReturnRequest::default())
+    );
+
+    // Replayed over a socket, as with `dockerfile-plus replay --serve`.
+    let socket = path.with_extension("sock");
+    let transport = Transport::Unix(socket.clone());
+    let served = transport.clone();
+    tokio::spawn(async move { served.serve(ReplayBridge::new(recording)).await });
+    let mut connected = transport.connect().await;
+    for _ in 0..100 {
+        if connected.is_ok() {
+            break;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        connected = transport.connect().await;
+    }
+    std::fs::remove_file(&socket).unwrap();
+    let mut replayed = Bridge::new(connected.unwrap());
+    let (found, missing) = read(&mut replayed).await;
+    assert_eq!(found, b"FROM alpine");
+    assert_eq!(
+        missing.downcast_ref::<Status>().unwrap().code(),
+        Code::NotFound
+    );
+    let unrecorded = replayed
+        .client()
+        .clone()
+        .read_file(frontend::ReadFileRequest {
+            file_path: "other".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(unrecorded.code(), Code::FailedPrecondition);
+}
+
+#[tokio::test]
+async fn replays_builds_that_depend_on_build_args() {
+    use buildkit_frontend::Reference;
+    use buildkit_llb::prelude::*;
+
+    use crate::mock_bridge::MockBridge;
+    use crate::options::DockerfileOptions;
+
+    /// Substitutes build args into the image of a one line Dockerfile, and solves it, as the
+    /// Dockerfile frontend puts build args into the LLB it solves.
+    struct FromImage;
+
+    #[tonic::async_trait]
+    impl Frontend for FromImage {
+        async fn run(&self, mut bridge: Bridge) -> Result<frontend::ReturnRequest> {
+            let options: DockerfileOptions =
+                buildkit_frontend::options::from_env(std::env::vars())?;
+            let context = Reference::new("context");
+            let dockerfile = bridge.read_file(&context, "Dockerfile", None).await?;
+            let mut image = String::from_utf8(dockerfile)?.trim()["FROM ".len()..].to_string();
+            for (name, value) in &options.build_args {
+                image = image.replace(&format!("${}", name), value);
+            }
+            let layer = bridge
+                .solve(Terminal::with(Source::image(image).output()))
+                .await?;
+            Ok(frontend::ReturnRequest {
+                result: Some(layer.into_result()),
+                error: None,
+            })
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "dockerfile-plus-build-args-{}.rec",
+        std::process::id()
+    ));
+    let upstream = MockBridge {
+        solve_result: Some(crate::layer("image")),
+        ..Default::default()
+    }
+    .with_file("Dockerfile", "FROM alpine:$VERSION\n");
+    let solves = upstream.solves.clone();
+    std::env::set_var(
+        format!("{}0", FRONTEND_OPT_PREFIX),
+        "build-arg:VERSION=3.12",
+    );
+    std::env::set_var(RECORD_ENV, &path);
+    let client = record_if_requested(upstream.connect().await).await;
+    std::env::remove_var(RECORD_ENV);
+    buildkit_frontend::run_with(&FromImage, Bridge::new(client.unwrap()))
+        .await
+        .unwrap();
+
+    let solved = encode(&solves.lock().unwrap()[0]);
+    assert!(String::from_utf8_lossy(&solved).contains("alpine:3.12"));
+    let recording = Recording::read(&path).unwrap();
+    assert_eq!(recording.options, vec!["build-arg:VERSION=3.12"]);
+
+    // The replayed build sends the same solve, which only matches with the recorded value.
+    let replayed = replay(&FromImage, path.to_str().unwrap()).await;
+    std::fs::remove_file(&path).unwrap();
+    assert!(replayed.unwrap());
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use buildkit_proto::moby::buildkit::v1::frontend;
+use serde::Serialize;
+
+use crate::expand::{Expanded, Origin, SourceFile};
+
+pub const REQUEST_TARGETS: &str = "frontend.targets";
+pub const REQUEST_OUTLINE: &str = "frontend.outline";
+pub const REQUEST_DESCRIBE: &str = "frontend.subrequests.describe";
+
+const SUBREQUESTS_VERSION: &str = "1.0.0";
+
+#[derive(Debug, Serialize)]
+struct Request {
+    name: &'static str,
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    description: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Target {
+    name: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    default: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    description: String,
+    base: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    platform: Option<String>,
+    location: Location,
+}
+
+#[derive(Debug, Serialize)]
+struct Arg {
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    location: Location,
+}
+
+#[derive(Debug, Serialize)]
+struct Outline {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    description: String,
+    args: Vec<Arg>,
+    sources: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TargetList {
+    targets: Vec<Target>,
+    sources: Vec<String>,
+}
+
+/// A line of one of the `sources`, in BuildKit's `pb.Location` format.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Location {
+    source_index: usize,
+    ranges: Vec<Range>,
+    /// The `INCLUDE+` directives leading to the line, innermost first. BuildKit doesn't know
+    /// about it and ignores it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    included_from: Vec<Location>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Range {
+    start: Position,
+    end: Position,
+}
+
+/// A 1-based line and 0-based character, as in BuildKit.
+#[derive(Debug, PartialEq, Serialize)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+/// A stage of the expanded Dockerfile, and the `ARG`s declared in it.
+#[derive(Debug)]
+struct Stage {
+    target: Target,
+    args: Vec<Arg>,
+}
+
+/// Answers a frontend subrequest about the expanded Dockerfile, instead of building it.
+pub fn handle(
+    request_id: &str,
+    expanded: &Expanded,
+    target: Option<&str>,
+) -> Result<frontend::Result> {
+    let (global_args, stages) = parse(expanded);
+    // Like BuildKit's `[][]byte`, the contents of the sources are base64 encoded.
+    let sources = expanded
+        .sources
+        .iter()
+        .map(|source| base64::encode(&source.contents))
+        .collect::<Vec<_>>();
+
+    let (json, text) = match request_id {
+        REQUEST_DESCRIBE => {
+            let requests = vec![
+                Request {
+                    name: REQUEST_OUTLINE,
+                    version: SUBREQUESTS_VERSION,
+                    kind: "rpc",
+                    description: "List all parameters current build target supports",
+                },
+                Request {
+                    name: REQUEST_TARGETS,
+                    version: SUBREQUESTS_VERSION,
+                    kind: "rpc",
+                    description: "List all targets current build supports",
+                },
+            ];
+            let text = requests
+                .iter()
+                .map(|request| format!("{}\t{}", request.name, request.description))
+                .collect::<Vec<_>>()
+                .join("\n");
+            (serde_json::to_vec_pretty(&requests)?, text)
+        }
+
+        REQUEST_TARGETS => {
+            let targets = stages
+                .into_iter()
+                .map(|stage| stage.target)
+                .collect::<Vec<_>>();
+            let text = targets
+                .iter()
+                .map(|target| {
+                    let name = if target.default {
+                        format!("{} (default)", target.name)
+                    } else {
+                        target.name.clone()
+                    };
+                    format!("{:<24}{}", name, target.description)
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let list = TargetList { targets, sources };
+            (serde_json::to_vec_pretty(&list)?, text)
+        }
+
+        REQUEST_OUTLINE => {
+            let stage = match target {
+                Some(target) => {
+                    match stages.into_iter().find(|stage| stage.target.name == target) {
+                        Some(stage) => stage,
+                        None => bail!("Target \"{}\" not found in the Dockerfile", target),
+                    }
+                }
+                None => match stages.into_iter().last() {
+                    Some(stage) => stage,
+                    None => bail!("The Dockerfile has no stages"),
+                },
+            };
+            let mut args = global_args;
+            args.extend(stage.args);
+            let text = args
+                .iter()
+                .map(|arg| {
+                    format!(
+                        "{:<24}{:<24}{}",
+                        arg.name,
+                        arg.value.as_deref().unwrap_or(""),
+                        arg.description
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            let outline = Outline {
+                name: stage.target.name,
+                description: stage.target.description,
+                args,
+                sources,
+            };
+            (serde_json::to_vec_pretty(&outline)?, text)
+        }
+
+        _ => bail!("Unsupported frontend subrequest \"{}\"", request_id),
+    };
+
+    let mut metadata = HashMap::new();
+    metadata.insert("result.json".to_string(), json);
+    metadata.insert("result.txt".to_string(), text.into_bytes());
+    metadata.insert(
+        "version".to_string(),
+        SUBREQUESTS_VERSION.as_bytes().to_vec(),
+    );
+
+    Ok(frontend::Result {
+        result: None,
+        metadata,
+    })
+}
+
+/// Finds the global `ARG`s and the stages of the expanded Dockerfile. The comment right above
+/// an instruction is used as its description.
+fn parse(expanded: &Expanded) -> (Vec<Arg>, Vec<Stage>) {
+    let mut global_args = vec![];
+    let mut stages: Vec<Stage> = vec![];
+    let mut comment = String::new();
+
+    for (line, origin) in expanded
+        .dockerfile
+        .lines()
+        .zip(expanded.include_map.lines.iter())
+    {
+        let line = line.trim();
+        if let Some(text) = line.strip_prefix('#') {
+            if is_parser_directive(text) {
+                continue;
+            }
+            if !comment.is_empty() {
+                comment.push(' ');
+            }
+            comment.push_str(text.trim());
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let instruction = words.next().map(str::to_ascii_uppercase);
+        let description = std::mem::take(&mut comment);
+
+        match instruction.as_deref() {
+            Some("FROM") => {
+                let mut platform = None;
+                let mut rest = vec![];
+                for word in words {
+                    match word.strip_prefix("--platform=") {
+                        Some(value) => platform = Some(value.to_string()),
+                        None => rest.push(word),
+                    }
+                }
+                let name = match rest.as_slice() {
+                    [_, as_keyword, name, ..] if as_keyword.eq_ignore_ascii_case("as") => {
+                        name.to_string()
+                    }
+                    _ => String::new(),
+                };
+                stages.push(Stage {
+                    target: Target {
+                        name,
+                        default: false,
+                        description,
+                        base: rest
+                            .first()
+                            .map(|base| base.to_string())
+                            .unwrap_or_default(),
+                        platform,
+                        location: locate(origin, &expanded.sources),
+                    },
+                    args: vec![],
+                });
+            }
+            Some("ARG") => {
+                for declaration in words {
+                    let mut parts = declaration.splitn(2, '=');
+                    let arg = Arg {
+                        name: parts.next().unwrap().to_string(),
+                        description: description.clone(),
+                        value: parts
+                            .next()
+                            .map(|value| value.trim_matches('"').to_string()),
+                        location: locate(origin, &expanded.sources),
+                    };
+                    match stages.last_mut() {
+                        Some(stage) => stage.args.push(arg),
+                        None => global_args.push(arg),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(stage) = stages.last_mut() {
+        stage.target.default = true;
+    }
+
+    (global_args, stages)
+}
+
+/// Where `origin` is in `sources`, which lists every file of the expansion.
+fn locate(origin: &Origin, sources: &[SourceFile]) -> Location {
+    let line = |line| Position { line, character: 0 };
+    Location {
+        source_index: sources
+            .iter()
+            .position(|source| source.file == origin.file)
+            .unwrap_or_default(),
+        ranges: vec![Range {
+            start: line(origin.line),
+            end: line(origin.line),
+        }],
+        included_from: origin
+            .include_chain
+            .iter()
+            .map(|include| locate(include, sources))
+            .collect(),
+    }
+}
+
+/// `# syntax=...` and `# escape=...` configure the parser, they don't describe anything.
+fn is_parser_directive(comment: &str) -> bool {
+    let mut parts = comment.splitn(2, '=');
+    let key = parts.next().unwrap().trim().to_ascii_lowercase();
+    parts.next().is_some() && (key == "syntax" || key == "escape")
+}
+
+#[test]
+fn targets_point_to_included_files() {
+    use crate::expand::IncludeMap;
+
+    let origin = |file: &str, line| Origin {
+        file: file.to_string(),
+        line,
+        include_chain: vec![],
+    };
+    let included = |file: &str, line| Origin {
+        include_chain: vec![origin("Dockerfile", 3)],
+        ..origin(file, line)
+    };
+    let source = |file: &str, contents: &str| SourceFile {
+        file: file.to_string(),
+        contents: contents.to_string(),
+    };
+    let expanded = Expanded {
+        dockerfile: [
+            "# syntax = edrevo/dockerfile-plus",
+            "ARG VERSION=3.12",
+            "# Base image for all services",
+            "FROM alpine:${VERSION} AS base",
+            "ARG USER",
+            "FROM --platform=linux/amd64 base",
+        ]
+        .join("\n"),
+        include_map: IncludeMap {
+            includes: vec![],
+            lines: vec![
+                origin("Dockerfile", 1),
+                origin("Dockerfile", 2),
+                included("base.Dockerfile", 1),
+                included("base.Dockerfile", 2),
+                included("base.Dockerfile", 3),
+                origin("Dockerfile", 4),
+            ],
+        },
+        sources: vec![
+            source("Dockerfile", "FROM base"),
+            source("base.Dockerfile", "FROM alpine"),
+        ],
+    };
+
+    let (global_args, stages) = parse(&expanded);
+
+    assert_eq!(global_args.len(), 1);
+    assert_eq!(global_args[0].name, "VERSION");
+    assert_eq!(global_args[0].value.as_deref(), Some("3.12"));
+    assert_eq!(global_args[0].description, "");
+
+    assert_eq!(stages.len(), 2);
+    assert_eq!(stages[0].target.name, "base");
+    assert_eq!(stages[0].target.base, "alpine:${VERSION}");
+    assert_eq!(stages[0].target.description, "Base image for all services");
+    assert!(!stages[0].target.default);
+    assert_eq!(stages[0].args[0].name, "USER");
+    assert_eq!(stages[1].target.name, "");
+    assert_eq!(stages[1].target.platform.as_deref(), Some("linux/amd64"));
+    assert!(stages[1].target.default);
+
+    assert_eq!(
+        serde_json::to_value(&stages[0].target.location).unwrap(),
+        serde_json::json!({
+            "sourceIndex": 1,
+            "ranges": [{"start": {"line": 2, "character": 0}, "end": {"line": 2, "character": 0}}],
+            "includedFrom": [{
+                "sourceIndex": 0,
+                "ranges": [{"start": {"line": 3, "character": 0}, "end": {"line": 3, "character": 0}}],
+            }],
+        })
+    );
+
+    let result = handle(REQUEST_TARGETS, &expanded, None).unwrap();
+    let list: serde_json::Value = serde_json::from_slice(&result.metadata["result.json"]).unwrap();
+    assert_eq!(list["targets"][1]["location"]["sourceIndex"], 0);
+    assert_eq!(
+        list["sources"],
+        serde_json::json!([base64::encode("FROM base"), base64::encode("FROM alpine")])
+    );
+}
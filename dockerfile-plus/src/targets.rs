@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use buildkit_proto::{
+    google::rpc::Status,
+    moby::buildkit::v1::frontend::{self, result::Result as RefResult, Ref, RefMap, ReturnRequest},
+};
+use futures::future::try_join_all;
+
+use crate::dockerfile_frontend::DockerfileFrontend;
+use crate::options::DockerfileOptions;
+
+/// Builds every target with the Dockerfile frontend, all at once, and returns a map of results
+/// keyed by target name.
+pub async fn solve_targets(
+    dockerfile_frontend: &DockerfileFrontend,
+    dockerfile_contents: &str,
+    targets: &[String],
+) -> Result<ReturnRequest> {
+    let solves = targets.iter().map(|target| async move {
+        let options = DockerfileOptions {
+            target: Some(target.clone()),
+            ..dockerfile_frontend.options().clone()
+        };
+        let result = dockerfile_frontend
+            .solve_with_options(dockerfile_contents, &options)
+            .await?;
+        Ok::<_, anyhow::Error>((target.clone(), result))
+    });
+    combine(try_join_all(solves).await?)
+}
+
+/// Combines the results of building each target into a single `RefMap` result. Metadata
+/// entries are suffixed with the target name, as BuildKit does for platforms.
+fn combine(results: Vec<(String, ReturnRequest)>) -> Result<ReturnRequest> {
+    let mut refs = HashMap::new();
+    let mut metadata = HashMap::new();
+
+    for (target, request) in results {
+        if let Some(error) = request.error {
+            return Ok(ReturnRequest {
+                result: None,
+                error: Some(Status {
+                    message: format!("target {}: {}", target, error.message),
+                    ..error
+                }),
+            });
+        }
+
+        let result = match request.result {
+            Some(result) => result,
+            None => bail!("Target \"{}\" returned no result", target),
+        };
+        let target_ref = match result.result {
+            Some(RefResult::RefDeprecated(id)) => Ref { id, def: None },
+            Some(RefResult::Ref(target_ref)) => target_ref,
+            Some(RefResult::RefsDeprecated(_)) | Some(RefResult::Refs(_)) => bail!(
+                "Target \"{}\" returned one result per platform, which can't be combined with other targets",
+                target
+            ),
+            None => bail!("Target \"{}\" returned no result", target),
+        };
+
+        for (key, value) in result.metadata {
+            metadata.insert(format!("{}/{}", key, target), value);
+        }
+        refs.insert(target, target_ref);
+    }
+
+    Ok(ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::Refs(RefMap { refs })),
+            metadata,
+        }),
+        error: None,
+    })
+}
+
+#[test]
+fn combines_results_by_target() {
+    let result = |id: &str| ReturnRequest {
+        result: Some(frontend::Result {
+            result: Some(RefResult::RefDeprecated(id.to_string())),
+            metadata: vec![("containerimage.config".to_string(), id.as_bytes().to_vec())]
+                .into_iter()
+                .collect(),
+        }),
+        error: None,
+    };
+
+    let combined = combine(vec![
+        ("api".to_string(), result("ref-api")),
+        ("worker".to_string(), result("ref-worker")),
+    ])
+    .unwrap()
+    .result
+    .unwrap();
+
+    match combined.result {
+        Some(RefResult::Refs(map)) => {
+            assert_eq!(map.refs["api"].id, "ref-api");
+            assert_eq!(map.refs["worker"].id, "ref-worker");
+        }
+        other => panic!("Unexpected result {:?}", other),
+    }
+    assert_eq!(
+        combined.metadata["containerimage.config/worker"],
+        b"ref-worker"
+    );
+}